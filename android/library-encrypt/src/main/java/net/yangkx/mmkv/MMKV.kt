@@ -7,8 +7,9 @@ import net.yangkx.mmkv.log.LoggerWrapper
 /**
  * @param dir a writeable directory, for example:
  * `context.getDir("mmkv", Context.MODE_PRIVATE)`
+ * @param key the hexadecimal encryption key, the store is not encrypted if it's null
  */
-class MMKV(dir: String, key: String) : AutoCloseable {
+class MMKV(dir: String, key: String? = null) : AutoCloseable {
     companion object {
 
         init {
@@ -20,7 +21,7 @@ class MMKV(dir: String, key: String) : AutoCloseable {
         private external fun attachLogger()
 
         @JvmStatic
-        private external fun initialize(dir: String, key: String): Long
+        private external fun initialize(dir: String, key: String?): Long
 
         @JvmStatic
         private external fun close(obj: Long)
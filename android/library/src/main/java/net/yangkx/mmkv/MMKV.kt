@@ -20,7 +20,7 @@ class MMKV(private val dir: String) : AutoCloseable {
         private external fun attachLogger()
 
         @JvmStatic
-        private external fun initialize(dir: String): Long
+        private external fun initialize(dir: String, key: String?): Long
 
         @JvmStatic
         private external fun close(obj: Long)
@@ -47,7 +47,7 @@ class MMKV(private val dir: String) : AutoCloseable {
     }
 
     @Volatile
-    private var nativeObj: Long = initialize(dir)
+    private var nativeObj: Long = initialize(dir, null)
 
     @Synchronized
     override fun close() {
@@ -33,9 +33,11 @@ public class MMKV {
      Initialize the MMKV instance.
      
      - Parameter dir: A writeable directory
+     - Parameter key: The hexadecimal encryption key, the store is not encrypted if it's nil,
+       which requires the library built with feature "encryption"
      */
-    public init(_ dir: String) {
-        rawPointer = RustMMKV.new_instance(dir)
+    public init(_ dir: String, key: String? = nil) {
+        rawPointer = RustMMKV.new_instance(dir, key)
     }
     
     deinit {
@@ -0,0 +1,465 @@
+use crate::Error::InvalidConfig;
+use crate::Result;
+#[cfg(feature = "compression")]
+use crate::core::compression::Compression;
+#[cfg(feature = "encryption")]
+use crate::core::config::Key;
+use crate::core::config::{Checksum, Options};
+use crate::core::recovery::RecoveryStrategy;
+use crate::mmkv::{DEFAULT_FILE_NAME, MMKV, page_size};
+use std::path::Path;
+
+/**
+Builder to tune a MMKV instance, created by [MMKV::builder], for example:
+```
+use mmkv::MMKV;
+
+let temp_dir = std::env::temp_dir();
+let builder = MMKV::builder(temp_dir.to_str().unwrap())
+    .file_name("builder_doc")
+    .initial_size(16 * 1024)
+    .growth(64 * 1024)
+    .populate(false);
+// An encrypted file can't be reopened as plaintext, keep it apart
+#[cfg(feature = "encryption")]
+let builder = builder
+    .file_name("builder_doc_encrypted")
+    .encryption_key("88C51C536176AD8A8EE4A06F62EE897E");
+let mmkv = builder.clone().open().unwrap();
+mmkv.put_sync("key", 1).unwrap();
+// An existing store can be opened read-only with the same options
+let read_only = builder.read_only(true).open().unwrap();
+assert_eq!(read_only.get("key"), Ok(1));
+mmkv.clear_data().unwrap();
+```
+The sizes are rounded up to a multiple of the page size.
+
+The options only take effect when the instance is opened for the first time in this process,
+opening a file which is already opened returns an instance sharing the existing mmap,
+see [MMKV::new].
+*/
+#[derive(Debug, Clone)]
+pub struct MMKVBuilder {
+    dir: String,
+    file_name: String,
+    initial_size: usize,
+    growth: usize,
+    populate: bool,
+    checksum: Checksum,
+    recovery: RecoveryStrategy,
+    #[cfg(feature = "compression")]
+    compression: Option<(Compression, usize)>,
+    multi_process: bool,
+    detect_changes: bool,
+    read_only: bool,
+    #[cfg(feature = "encryption")]
+    key: Option<Result<Key>>,
+}
+
+impl MMKVBuilder {
+    pub(crate) fn new(dir: &str) -> Self {
+        MMKVBuilder {
+            dir: dir.to_string(),
+            file_name: DEFAULT_FILE_NAME.to_string(),
+            initial_size: page_size(),
+            growth: page_size(),
+            populate: true,
+            checksum: Checksum::default(),
+            recovery: RecoveryStrategy::default(),
+            #[cfg(feature = "compression")]
+            compression: None,
+            multi_process: false,
+            detect_changes: false,
+            read_only: false,
+            #[cfg(feature = "encryption")]
+            key: None,
+        }
+    }
+
+    /// The name of the data file inside the directory, `mini_mmkv` by default,
+    /// see [MMKV::with_id] for the accepted names.
+    pub fn file_name(mut self, file_name: &str) -> Self {
+        self.file_name = file_name.to_string();
+        self
+    }
+
+    /// The size of the data file when it's created, one page by default.
+    pub fn initial_size(mut self, size: usize) -> Self {
+        self.initial_size = size;
+        self
+    }
+
+    /// The minimal size added to the data file each time it's full, one page by default,
+    /// the file doubles its size until the step reaches 32 MB, then grows linearly.
+    pub fn growth(mut self, size: usize) -> Self {
+        self.growth = size;
+        self
+    }
+
+    /// Whether to prefault the whole file into memory when it's mapped, enabled by default,
+    /// disable it for large stores which are only partially read.
+    pub fn populate(mut self, populate: bool) -> Self {
+        self.populate = populate;
+        self
+    }
+
+    /// The checksum appended to each record to detect corruption, [Checksum::Crc32c] by default.
+    ///
+    /// It applies to new files and the files rewritten by compaction, existing records are read
+    /// with the checksum they were written with, including the CRC8 of older versions.
+    /// It's not used by the encrypted stores, which authenticate the records instead.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// How to load the data file if some records are corrupted, [RecoveryStrategy::Stop] by default,
+    /// see [MMKV::recovery_report] for what was lost.
+    pub fn recovery(mut self, recovery: RecoveryStrategy) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Compress the values with at least `threshold` bytes by `algorithm`, disabled by default.
+    ///
+    /// The algorithm is recorded with each value, so the values written with another algorithm
+    /// or without compression are still readable, the values which don't get smaller are kept.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, algorithm: Compression, threshold: usize) -> Self {
+        self.compression = Some((algorithm, threshold));
+        self
+    }
+
+    /// Share the data file with other processes, disabled by default.
+    ///
+    /// The file is locked with `flock` while it's read or written, and the changes of other
+    /// processes are loaded before each read and write, all processes must enable it.
+    /// The observers are not notified of the changes of other processes, and
+    /// [MMKV::update] is only atomic within one process.
+    pub fn multi_process(mut self, multi_process: bool) -> Self {
+        self.multi_process = multi_process;
+        self
+    }
+
+    /// Reload the data file if it's replaced or modified by others than this instance,
+    /// such as restoring a backup, disabled by default.
+    ///
+    /// The file is checked before each read and write, the changes can also be loaded
+    /// explicitly by [MMKV::reload].
+    pub fn detect_changes(mut self, detect_changes: bool) -> Self {
+        self.detect_changes = detect_changes;
+        self
+    }
+
+    /// Open the existing data file without writing it, see [MMKV::open_read_only],
+    /// disabled by default. The sizes are not used as the file is never resized.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The hexadecimal key of 16 or 32 bytes to encrypt the data file, the records are protected
+    /// by the [checksum](MMKVBuilder::checksum) instead if it's not set, so encrypted and
+    /// plaintext stores can be opened in the same build.
+    /// A malformed key fails [open](MMKVBuilder::open) with [InvalidKey](crate::Error::InvalidKey).
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: &str) -> Self {
+        self.key = Some(Key::from_hex(key));
+        self
+    }
+
+    /// The raw key of 16 bytes for AES-128 or 32 bytes for AES-256, see
+    /// [encryption_key](MMKVBuilder::encryption_key).
+    #[cfg(feature = "encryption")]
+    pub fn raw_encryption_key(mut self, key: &[u8]) -> Self {
+        self.key = Some(Key::from_bytes(key));
+        self
+    }
+
+    /// Open the instance with the options of this builder.
+    pub fn open(self) -> Result<MMKV> {
+        let options = Options {
+            initial_size: MMKVBuilder::round_to_page("initial size", self.initial_size)?,
+            growth: MMKVBuilder::round_to_page("growth", self.growth)?,
+            populate: self.populate,
+            checksum: self.checksum,
+            recovery: self.recovery,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            multi_process: self.multi_process,
+            detect_changes: self.detect_changes,
+            read_only: self.read_only,
+            #[cfg(feature = "encryption")]
+            key: self.key.transpose()?,
+        };
+        if self.read_only {
+            MMKV::check_id(&self.file_name)?;
+            let path = Path::new(&self.dir).join(&self.file_name);
+            return MMKV::open_read_only_with(&path, options);
+        }
+        MMKV::open(&self.dir, &self.file_name, options)
+    }
+
+    /// Open the instance encrypted with the hexadecimal `key` if it's set, for the bindings
+    /// which choose it at runtime, the key is rejected without feature "encryption"
+    pub(crate) fn open_with_key(self, key: Option<&str>) -> Result<MMKV> {
+        match key {
+            None => self.open(),
+            #[cfg(feature = "encryption")]
+            Some(key) => self.encryption_key(key).open(),
+            #[cfg(not(feature = "encryption"))]
+            Some(_) => Err(InvalidConfig(
+                "the key is not supported without feature \"encryption\"".to_string(),
+            )),
+        }
+    }
+
+    fn round_to_page(name: &str, size: usize) -> Result<u64> {
+        if size == 0 {
+            return Err(InvalidConfig(format!("{name} should not be 0")));
+        }
+        size.checked_next_multiple_of(page_size())
+            .map(|size| size as u64)
+            .ok_or_else(|| InvalidConfig(format!("{name} {size} is too large")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::Error::InvalidConfig;
+    use crate::mmkv::page_size;
+    use crate::{Checksum, MMKV};
+
+    #[test]
+    fn test_builder() {
+        let file_name = "test_builder";
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+        let builder = MMKV::builder(".")
+            .file_name(file_name)
+            .initial_size(page_size() + 1)
+            .growth(page_size() * 2)
+            .populate(false)
+            .checksum(Checksum::Xxh3);
+        #[cfg(feature = "encryption")]
+        let builder = builder.encryption_key("88C51C536176AD8A8EE4A06F62EE897E");
+        assert!(matches!(
+            builder.clone().growth(0).open(),
+            Err(InvalidConfig(_))
+        ));
+        let mmkv = builder.open().unwrap();
+        #[cfg(not(feature = "encryption"))]
+        assert_eq!(
+            fs::read(file_name).unwrap()[6],
+            Checksum::Xxh3.codec() as u8
+        );
+        let file_len = || fs::metadata(file_name).unwrap().len() as usize;
+        assert_eq!(file_len(), page_size() * 2);
+        mmkv.put_sync("key", vec![0u8; page_size() * 2].as_slice())
+            .unwrap();
+        assert_eq!(file_len(), page_size() * 4);
+        assert_eq!(mmkv.get::<Vec<u8>>("key"), Ok(vec![0u8; page_size() * 2]));
+        mmkv.clear_data().unwrap();
+        // clear_data keeps the options of the instance
+        assert_eq!(file_len(), page_size() * 2);
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_builder_compression() {
+        use crate::Compression;
+
+        let file_name = "test_builder_compression";
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+        let builder = MMKV::builder(".").file_name(file_name);
+        #[cfg(feature = "encryption")]
+        let builder = builder.encryption_key("88C51C536176AD8A8EE4A06F62EE897E");
+        let large = "large value ".repeat(100);
+
+        let mmkv = builder
+            .clone()
+            .compression(Compression::Lz4, 64)
+            .open()
+            .unwrap();
+        mmkv.put("lz4", large.as_str()).unwrap();
+        mmkv.put("small", "small").unwrap();
+        drop(mmkv);
+
+        // The values compressed differently coexist in the file
+        let mmkv = builder.clone().open().unwrap();
+        mmkv.put("plain", large.as_str()).unwrap();
+        drop(mmkv);
+        let mmkv = builder.compression(Compression::Zstd, 64).open().unwrap();
+        mmkv.put("zstd", large.as_str()).unwrap();
+        for key in ["lz4", "plain", "zstd"] {
+            assert_eq!(mmkv.get::<String>(key), Ok(large.clone()));
+        }
+        assert_eq!(mmkv.get::<String>("small"), Ok("small".to_string()));
+        for (_, entry) in mmkv.iter().unwrap() {
+            assert!(entry.bytes().is_ok());
+        }
+        mmkv.clear_data().unwrap();
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+    }
+
+    #[test]
+    fn test_open_with_key() {
+        let file_name = "test_open_with_key";
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+        let builder = MMKV::builder(".").file_name(file_name);
+        let key = "88C51C536176AD8A8EE4A06F62EE897E";
+        #[cfg(not(feature = "encryption"))]
+        assert!(matches!(
+            builder.clone().open_with_key(Some(key)),
+            Err(InvalidConfig(_))
+        ));
+        #[cfg(feature = "encryption")]
+        {
+            let mmkv = builder.clone().open_with_key(Some(key)).unwrap();
+            mmkv.put("key", 1).unwrap();
+            assert_eq!(mmkv.get("key"), Ok(1));
+            mmkv.clear_data().unwrap();
+            drop(mmkv);
+            let _ = fs::remove_file(file_name);
+            let _ = fs::remove_file(format!("{file_name}.meta"));
+        }
+        let mmkv = builder.open_with_key(None).unwrap();
+        mmkv.put("key", 2).unwrap();
+        assert_eq!(mmkv.get("key"), Ok(2));
+        mmkv.clear_data().unwrap();
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_builder_encryption_is_optional() {
+        use crate::Error::{CodecMismatch, WrongKey};
+
+        let (plain_file, secret_file) = ("test_builder_plain", "test_builder_secret");
+        for file_name in [plain_file, secret_file] {
+            let _ = fs::remove_file(file_name);
+            let _ = fs::remove_file(format!("{file_name}.meta"));
+        }
+        let key = "88C51C536176AD8A8EE4A06F62EE897E";
+        let plain = MMKV::builder(".").file_name(plain_file).open().unwrap();
+        let secret = MMKV::builder(".")
+            .file_name(secret_file)
+            .encryption_key(key)
+            .open()
+            .unwrap();
+        plain.put("key", "plain").unwrap();
+        secret.put("key", "secret").unwrap();
+        assert_eq!(
+            fs::read(plain_file).unwrap()[6],
+            Checksum::Crc32c.codec() as u8
+        );
+        assert!(!fs::exists(format!("{plain_file}.meta")).unwrap());
+        assert!(fs::exists(format!("{secret_file}.meta")).unwrap());
+
+        // The opened instances are only shared with the same key
+        let builder = MMKV::builder(".").file_name(plain_file);
+        assert!(matches!(
+            builder.clone().encryption_key(key).open(),
+            Err(CodecMismatch(_))
+        ));
+        assert!(matches!(
+            MMKV::builder(".").file_name(secret_file).open(),
+            Err(CodecMismatch(_))
+        ));
+        let wrong_key = MMKV::builder(".")
+            .file_name(secret_file)
+            .encryption_key("0F1E2D3C4B5A69788796A5B4C3D2E1F0");
+        assert!(matches!(wrong_key.clone().open(), Err(WrongKey)));
+        drop(plain);
+        drop(secret);
+        let encrypted = fs::read(secret_file).unwrap();
+        assert!(matches!(wrong_key.open(), Err(WrongKey)));
+        assert_eq!(fs::read(secret_file).unwrap(), encrypted);
+        // And the files are rejected with the other codec
+        assert!(matches!(
+            builder.encryption_key(key).open(),
+            Err(CodecMismatch(_))
+        ));
+        assert!(matches!(
+            MMKV::builder(".").file_name(secret_file).open(),
+            Err(CodecMismatch(_))
+        ));
+
+        let plain = MMKV::builder(".").file_name(plain_file).open().unwrap();
+        let secret = MMKV::with_raw_key(".", secret_file, &hex::decode(key).unwrap()).unwrap();
+        assert_eq!(plain.get("key"), Ok("plain".to_string()));
+        assert_eq!(secret.get("key"), Ok("secret".to_string()));
+        plain.put_sync("key", "plain").unwrap();
+        secret.put_sync("key", "secret").unwrap();
+        // Both are opened read-only as well
+        let read_only = MMKV::open_read_only(plain_file).unwrap();
+        assert_eq!(read_only.get("key"), Ok("plain".to_string()));
+        let read_only = MMKV::builder(".")
+            .file_name(secret_file)
+            .encryption_key(key)
+            .read_only(true)
+            .open()
+            .unwrap();
+        assert_eq!(read_only.get("key"), Ok("secret".to_string()));
+        drop(read_only);
+        plain.clear_data().unwrap();
+        secret.clear_data().unwrap();
+        for file_name in [plain_file, secret_file] {
+            let _ = fs::remove_file(file_name);
+            let _ = fs::remove_file(format!("{file_name}.meta"));
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_builder_encryption_key_errors() {
+        use crate::Error::{IOError, InvalidKey};
+
+        let file_name = "test_builder_encryption_key_errors";
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_dir(format!("{file_name}.meta"));
+        let builder = MMKV::builder(".").file_name(file_name);
+        for key in ["not hex", "88C51C53", ""] {
+            assert!(matches!(
+                builder.clone().encryption_key(key).open(),
+                Err(InvalidKey(_))
+            ));
+        }
+        assert!(matches!(
+            builder.clone().raw_encryption_key(&[0; 24]).open(),
+            Err(InvalidKey(_))
+        ));
+        assert!(!fs::exists(file_name).unwrap());
+
+        // The raw key is the same as its hexadecimal form
+        let key = [0x5Au8; 32];
+        let mmkv = builder.clone().raw_encryption_key(&key).open().unwrap();
+        mmkv.put("key", 1).unwrap();
+        drop(mmkv);
+        let mmkv = builder
+            .clone()
+            .encryption_key(&hex::encode(key))
+            .open()
+            .unwrap();
+        assert_eq!(mmkv.get("key"), Ok(1));
+        mmkv.clear_data().unwrap();
+        drop(mmkv);
+
+        // The nonce can't be written to the meta file
+        fs::remove_file(format!("{file_name}.meta")).unwrap();
+        fs::create_dir(format!("{file_name}.meta")).unwrap();
+        assert!(matches!(
+            builder.raw_encryption_key(&key).open(),
+            Err(IOError(_))
+        ));
+        let _ = fs::remove_file(file_name);
+        fs::remove_dir(format!("{file_name}.meta")).unwrap();
+    }
+}
@@ -1,10 +1,18 @@
+use std::borrow::Cow;
 use std::mem::size_of;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{f32, f64, str, vec};
 
 use crate::Error::{DataInvalid, DecodeFailed, KeyNotFound, TypeMissMatch};
 use crate::Result;
-use kv::KV;
+#[cfg(feature = "compression")]
+use crate::core::compression::Compression;
+use crate::core::compression::decompress;
+use crate::core::config::Checksum;
+use crate::core::header::Codec;
+use kv::{Batch, KV};
 use protobuf::Message;
 
 include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
@@ -14,6 +22,20 @@ pub struct Buffer(Arc<KV>);
 
 pub trait Encoder: Send {
     fn encode_to_bytes(&self, raw_buffer: &Buffer, position: u32) -> Result<Vec<u8>>;
+
+    /// The codec recorded in the header of the file
+    fn codec(&self) -> Codec;
+
+    /// Create an encoder for a new file at `path` which is going to replace the current one,
+    /// the records are appended with `checksum` if the encoder doesn't encrypt them
+    fn for_new_file(&self, path: &Path, checksum: Checksum) -> Result<Box<dyn Encoder>>;
+
+    /// Create an encoder for the file at `path` which is written by another process
+    /// and has replaced the current one, the records in it are encoded with `codec`
+    fn for_existing_file(&self, path: &Path, codec: Codec) -> Result<Box<dyn Encoder>>;
+
+    /// The decoder of the records encoded by this encoder
+    fn decoder(&self) -> Box<dyn Decoder>;
 }
 
 pub struct DecodeResult {
@@ -21,10 +43,26 @@ pub struct DecodeResult {
     pub len: u32,
 }
 
+/// Records are framed by a 4 bytes big-endian length of the rest of the record
 pub trait Decoder {
     fn decode_bytes(&self, data: &[u8], position: u32) -> Result<DecodeResult>;
 }
 
+/// The size of the length prefix of each record
+pub const LEN_PREFIX: usize = size_of::<u32>();
+
+/// The rest of the record at the start of `data` after the length prefix,
+/// [DataInvalid] if the length is shorter than `min_len` bytes or exceeds `data`
+pub fn record_body(data: &[u8], min_len: usize) -> Result<&[u8]> {
+    let len_bytes = data.get(0..LEN_PREFIX).ok_or(DataInvalid)?;
+    let item_len = u32::from_be_bytes(len_bytes.try_into().map_err(|_| DataInvalid)?) as usize;
+    if item_len < min_len.max(1) {
+        return Err(DataInvalid);
+    }
+    let end = LEN_PREFIX.checked_add(item_len).ok_or(DataInvalid)?;
+    data.get(LEN_PREFIX..end).ok_or(DataInvalid)
+}
+
 impl Buffer {
     fn from_kv(key: &str, t: i32, value: Vec<u8>) -> Self {
         let mut kv = KV::new();
@@ -40,13 +78,85 @@ impl Buffer {
 
     pub fn parse<T: ProvideTypeToken + FromBytes>(&self) -> Result<T> {
         self.check_buffer_type(T::type_token())?;
-        T::from_bytes(self.0.value.as_slice())
+        T::from_bytes(&self.plain_value()?)
+    }
+
+    /// Compress the value with `algorithm` if it has at least `threshold` bytes
+    /// and gets smaller, the algorithm is recorded so [Buffer::parse] decompresses it
+    #[cfg(feature = "compression")]
+    pub fn compress(mut self, algorithm: Compression, threshold: usize) -> Result<Self> {
+        if self.is_batch()
+            || self.is_deleting()
+            || self.0.compression != 0
+            || self.value().len() < threshold
+        {
+            return Ok(self);
+        }
+        let compressed = algorithm.compress(self.value())?;
+        if compressed.len() < self.value().len() {
+            let kv = Arc::make_mut(&mut self.0);
+            kv.value = compressed;
+            kv.compression = algorithm.id();
+        }
+        Ok(self)
     }
 
     pub fn deleted_buffer(key: &str) -> Self {
         Buffer::from_kv(key, InnerTypes::Deleted.value(), vec![])
     }
 
+    /// Expire the buffer after `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        let ttl = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+        Arc::make_mut(&mut self.0).expire_at = now_millis().saturating_add(ttl);
+        self
+    }
+
+    pub fn has_ttl(&self) -> bool {
+        self.0.expire_at != 0
+    }
+
+    pub fn expire_at(&self) -> Option<SystemTime> {
+        self.has_ttl()
+            .then(|| UNIX_EPOCH + Duration::from_millis(self.0.expire_at.max(0) as u64))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.has_ttl() && now_millis() >= self.0.expire_at
+    }
+
+    /// Pack multiple buffers into one buffer, so they can be written as one record
+    pub fn batch_buffer(buffers: &[Buffer]) -> Self {
+        let mut batch = Batch::new();
+        batch.items = buffers
+            .iter()
+            .map(|buffer| buffer.0.as_ref().clone())
+            .collect();
+        Buffer::from_kv(
+            "",
+            InnerTypes::Batch.value(),
+            batch.write_to_bytes().unwrap(),
+        )
+    }
+
+    pub fn is_batch(&self) -> bool {
+        self.0.type_ == InnerTypes::Batch.value()
+    }
+
+    /// Unpack the buffers packed by [Buffer::batch_buffer]
+    pub fn batch_items(&self) -> Result<Vec<Buffer>> {
+        if !self.is_batch() {
+            return Err(TypeMissMatch);
+        }
+        let batch =
+            Batch::parse_from_bytes(self.value()).map_err(|e| DecodeFailed(e.to_string()))?;
+        Ok(batch
+            .items
+            .into_iter()
+            .map(|kv| Buffer(Arc::new(kv)))
+            .collect())
+    }
+
     pub fn from_encoded_bytes(data: &[u8]) -> Result<Self> {
         let kv = KV::parse_from_bytes(data).map_err(|e| DecodeFailed(e.to_string()))?;
         Ok(Buffer(Arc::new(kv)))
@@ -60,11 +170,27 @@ impl Buffer {
         self.0.key.as_str()
     }
 
-    #[allow(dead_code)]
+    /// The stored bytes of the value, which may be compressed, see [Buffer::plain_value]
     pub fn value(&self) -> &[u8] {
         self.0.value.as_slice()
     }
 
+    /// The value produced by [ToBytes], decompressed if it's compressed
+    pub fn plain_value(&self) -> Result<Cow<'_, [u8]>> {
+        match self.0.compression {
+            0 => Ok(Cow::Borrowed(self.value())),
+            id => decompress(id, self.value()).map(Cow::Owned),
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        ValueType::from_token(self.0.type_)
+    }
+
+    pub fn is_type<T: ProvideTypeToken>(&self) -> bool {
+        !self.is_deleting() && T::type_token().token == self.0.type_
+    }
+
     pub fn is_deleting(&self) -> bool {
         self.0.type_ == InnerTypes::Deleted.value()
     }
@@ -86,6 +212,13 @@ impl Buffer {
     }
 }
 
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 enum InnerTypes {
     I32 = 0,
@@ -99,6 +232,7 @@ enum InnerTypes {
     I64Array = 8,
     F32Array = 9,
     F64Array = 10,
+    Batch = 99,
     Deleted = 100,
 }
 
@@ -112,6 +246,43 @@ impl InnerTypes {
     }
 }
 
+/// The type of a stored value, see [crate::Entry::value_type]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum ValueType {
+    I32,
+    Str,
+    Bool,
+    I64,
+    F32,
+    F64,
+    ByteArray,
+    I32Array,
+    I64Array,
+    F32Array,
+    F64Array,
+    /// Custom type, with the token provided by [TypeToken::new]
+    Custom(i32),
+}
+
+impl ValueType {
+    fn from_token(token: i32) -> Self {
+        match token {
+            t if t == InnerTypes::I32.value() => ValueType::I32,
+            t if t == InnerTypes::Str.value() => ValueType::Str,
+            t if t == InnerTypes::Byte.value() => ValueType::Bool,
+            t if t == InnerTypes::I64.value() => ValueType::I64,
+            t if t == InnerTypes::F32.value() => ValueType::F32,
+            t if t == InnerTypes::F64.value() => ValueType::F64,
+            t if t == InnerTypes::ByteArray.value() => ValueType::ByteArray,
+            t if t == InnerTypes::I32Array.value() => ValueType::I32Array,
+            t if t == InnerTypes::I64Array.value() => ValueType::I64Array,
+            t if t == InnerTypes::F32Array.value() => ValueType::F32Array,
+            t if t == InnerTypes::F64Array.value() => ValueType::F64Array,
+            t => ValueType::Custom(t),
+        }
+    }
+}
+
 /// 0 ~ 100 reserved for internal usage.
 pub struct TypeToken {
     token: i32,
@@ -306,12 +477,11 @@ macro_rules! impl_from_buffer_for_number {
         impl FromBytes for $t {
             fn from_bytes(bytes: &[u8]) -> Result<Self> {
                 const ITEM_SIZE: usize = size_of::<$t>() / size_of::<u8>();
-                let array_result: std::result::Result<[u8; ITEM_SIZE], _> =
-                    bytes[0..ITEM_SIZE].try_into();
-                match array_result {
-                    Ok(array) => Ok(<$t>::from_be_bytes(array)),
-                    Err(_) => Err(DataInvalid),
-                }
+                let array: [u8; ITEM_SIZE] = bytes
+                    .get(0..ITEM_SIZE)
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or(DataInvalid)?;
+                Ok(<$t>::from_be_bytes(array))
             }
         }
         )+
@@ -325,6 +495,36 @@ impl_from_buffer_for_number!(
     (f64, Types::F64);
 );
 
+/// Numeric types which can be used in [crate::MMKV::increment]
+pub trait Increment: ProvideTypeToken + ToBytes + FromBytes + Copy {
+    /// Add `delta` to self, returns `None` if overflowed
+    fn checked_increment(self, delta: Self) -> Option<Self>;
+}
+
+macro_rules! impl_increment {
+    ($($t:ty),+; checked) => {
+        $(
+        impl Increment for $t {
+            fn checked_increment(self, delta: Self) -> Option<Self> {
+                self.checked_add(delta)
+            }
+        }
+        )+
+    };
+    ($($t:ty),+; float) => {
+        $(
+        impl Increment for $t {
+            fn checked_increment(self, delta: Self) -> Option<Self> {
+                Some(self + delta)
+            }
+        }
+        )+
+    };
+}
+
+impl_increment!(i32, i64; checked);
+impl_increment!(f32, f64; float);
+
 macro_rules! impl_from_buffer_for_typed_array {
     ($(($t:ty, $kv_type:expr)),+;) => {
         $(
@@ -456,4 +656,30 @@ mod tests {
         assert_eq!(clone.parse::<Vec<u8>>(), Ok(bytes));
         assert_eq!(buffer, clone);
     }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_buffer_compress() {
+        use crate::core::compression::Compression;
+
+        let json = r#"{"name":"mmkv","tags":["a","b"]}"#.repeat(32);
+        let buffer = Buffer::new("json", json.as_str());
+        let small = buffer.clone().compress(Compression::Lz4, json.len() + 1);
+        assert!(small.unwrap().shared_with(&buffer));
+
+        for algorithm in [Compression::Lz4, Compression::Zstd] {
+            let compressed = buffer.clone().compress(algorithm, 64).unwrap();
+            assert!(compressed.value().len() < json.len());
+            let copy = Buffer::from_encoded_bytes(&compressed.to_bytes()).unwrap();
+            assert_eq!(copy.parse::<String>(), Ok(json.clone()));
+            assert_eq!(copy.plain_value().unwrap().as_ref(), json.as_bytes());
+        }
+        // Incompressible values are kept as is
+        let random: Vec<u8> = (0..64u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let buffer = Buffer::new("random", random.as_slice());
+        let kept = buffer.clone().compress(Compression::Zstd, 0).unwrap();
+        assert_eq!(kept, buffer);
+    }
 }
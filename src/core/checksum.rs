@@ -0,0 +1,136 @@
+use crate::Error::{CodecMismatch, DecodeFailed};
+use crate::Result;
+use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder, LEN_PREFIX, record_body};
+use crate::core::config::Checksum;
+use crate::core::crc::CrcEncoderDecoder;
+use crate::core::header::Codec;
+use crc::{CRC_32_ISCSI, Crc};
+use std::mem::size_of;
+use std::path::Path;
+use xxhash_rust::xxh3::xxh3_64;
+
+const LOG_TAG: &str = "MMKV:Checksum";
+
+const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Encode records with a 32 or 64 bits checksum appended
+#[derive(Clone, Copy)]
+pub struct ChecksumEncoderDecoder(pub Checksum);
+
+impl ChecksumEncoderDecoder {
+    fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+        match self.0 {
+            Checksum::Crc32c => CRC32C.checksum(bytes).to_be_bytes().to_vec(),
+            Checksum::Xxh3 => xxh3_64(bytes).to_be_bytes().to_vec(),
+        }
+    }
+
+    fn digest_len(&self) -> usize {
+        match self.0 {
+            Checksum::Crc32c => size_of::<u32>(),
+            Checksum::Xxh3 => size_of::<u64>(),
+        }
+    }
+}
+
+impl Encoder for ChecksumEncoderDecoder {
+    fn encode_to_bytes(&self, raw_buffer: &Buffer, _: u32) -> Result<Vec<u8>> {
+        let bytes_to_write = raw_buffer.to_bytes();
+        let digest = self.digest(bytes_to_write.as_slice());
+        let len = (bytes_to_write.len() + digest.len()) as u32;
+        let mut data = len.to_be_bytes().to_vec();
+        data.extend_from_slice(bytes_to_write.as_slice());
+        data.extend_from_slice(digest.as_slice());
+        Ok(data)
+    }
+
+    fn codec(&self) -> Codec {
+        self.0.codec()
+    }
+
+    fn for_new_file(&self, _: &Path, checksum: Checksum) -> Result<Box<dyn Encoder>> {
+        Ok(Box::new(ChecksumEncoderDecoder(checksum)))
+    }
+
+    fn for_existing_file(&self, _: &Path, codec: Codec) -> Result<Box<dyn Encoder>> {
+        codec_pair(codec).map(|(encoder, _)| encoder)
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        Box::new(*self)
+    }
+}
+
+impl Decoder for ChecksumEncoderDecoder {
+    fn decode_bytes(&self, data: &[u8], _: u32) -> Result<DecodeResult> {
+        let body = record_body(data, self.digest_len())?;
+        let read_len = (LEN_PREFIX + body.len()) as u32;
+        let (bytes_to_decode, digest) = body.split_at(body.len() - self.digest_len());
+        let result = if self.digest(bytes_to_decode) == digest {
+            Buffer::from_encoded_bytes(bytes_to_decode)
+        } else {
+            Err(DecodeFailed(format!("{:?} check failed", self.0)))
+        };
+        let buffer = match result {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!(LOG_TAG, "Failed to decode data, reason: {:?}", e);
+                None
+            }
+        };
+        Ok(DecodeResult {
+            buffer,
+            len: read_len,
+        })
+    }
+}
+
+/// Create the encoder and decoder for the records encoded with `codec`
+pub fn codec_pair(codec: Codec) -> Result<(Box<dyn Encoder>, Box<dyn Decoder>)> {
+    match codec {
+        Codec::Crc8 => Ok((Box::new(CrcEncoderDecoder), Box::new(CrcEncoderDecoder))),
+        Codec::Crc32c => {
+            let codec = ChecksumEncoderDecoder(Checksum::Crc32c);
+            Ok((Box::new(codec), Box::new(codec)))
+        }
+        Codec::Xxh3 => {
+            let codec = ChecksumEncoderDecoder(Checksum::Xxh3);
+            Ok((Box::new(codec), Box::new(codec)))
+        }
+        Codec::AesEax => Err(CodecMismatch("encrypted file requires a key".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Error::DataInvalid;
+    use crate::core::buffer::{Buffer, Decoder, Encoder};
+    use crate::core::checksum::ChecksumEncoderDecoder;
+    use crate::core::config::Checksum;
+
+    #[test]
+    fn test_checksum_buffer() {
+        for checksum in [Checksum::Crc32c, Checksum::Xxh3] {
+            let codec = ChecksumEncoderDecoder(checksum);
+            let buffer = Buffer::new("key", 1);
+            let mut bytes = codec.encode_to_bytes(&buffer, 0).unwrap();
+            let decode_result = codec.decode_bytes(bytes.as_slice(), 0).unwrap();
+            assert_eq!(decode_result.len, bytes.len() as u32);
+            assert_eq!(decode_result.buffer, Some(buffer));
+
+            // Flip one bit of the value
+            let index = bytes.len() - codec.digest_len() - 1;
+            bytes[index] ^= 1;
+            let decode_result = codec.decode_bytes(bytes.as_slice(), 0).unwrap();
+            assert_eq!(decode_result.len, bytes.len() as u32);
+            assert_eq!(decode_result.buffer, None);
+
+            // Shorter than the digest, or longer than the data
+            let mut short = 2u32.to_be_bytes().to_vec();
+            short.extend([0; 2]);
+            for bytes in [short.as_slice(), &bytes[..bytes.len() - 1]] {
+                assert!(matches!(codec.decode_bytes(bytes, 0), Err(DataInvalid)));
+            }
+        }
+    }
+}
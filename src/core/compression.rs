@@ -0,0 +1,124 @@
+#[cfg(not(feature = "compression"))]
+use crate::Error::DecodeFailed;
+#[cfg(feature = "compression")]
+use crate::Error::{DataInvalid, DecodeFailed, EncodeFailed};
+use crate::Result;
+#[cfg(feature = "compression")]
+use std::io::Read;
+
+// The zstd level, the default of the zstd cli
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+// The max ratio of lz4, a larger prepended size means the value is malformed
+#[cfg(feature = "compression")]
+const LZ4_MAX_RATIO: usize = 255;
+// The max ratio of zstd, a raw block of 128 KiB repeating one byte takes 4 bytes
+#[cfg(feature = "compression")]
+const ZSTD_MAX_RATIO: usize = 32 * 1024;
+
+/// The algorithm to compress large values, see [MMKVBuilder::compression](crate::MMKVBuilder::compression)
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// Fast, with a moderate ratio
+    Lz4 = 1,
+    /// Slower, with a better ratio
+    Zstd = 2,
+}
+
+#[cfg(feature = "compression")]
+impl Compression {
+    /// The id recorded with each compressed value, 0 means not compressed
+    pub(crate) fn id(self) -> i32 {
+        self as i32
+    }
+
+    pub(crate) fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            Compression::Zstd => {
+                zstd::encode_all(bytes, ZSTD_LEVEL).map_err(|e| EncodeFailed(format!("zstd: {e}")))
+            }
+        }
+    }
+}
+
+/// Decompress the value compressed with the algorithm of `id`
+#[cfg(feature = "compression")]
+pub fn decompress(id: i32, bytes: &[u8]) -> Result<Vec<u8>> {
+    if id == Compression::Lz4.id() {
+        // The size of the value is prepended as u32 little-endian by lz4_flex
+        let size_bytes = bytes.get(0..4).ok_or(DataInvalid)?;
+        let size = u32::from_le_bytes(size_bytes.try_into().map_err(|_| DataInvalid)?) as usize;
+        let compressed = &bytes[4..];
+        if size > compressed.len().saturating_mul(LZ4_MAX_RATIO) {
+            return Err(DataInvalid);
+        }
+        lz4_flex::decompress(compressed, size).map_err(|e| DecodeFailed(format!("lz4: {e}")))
+    } else if id == Compression::Zstd.id() {
+        zstd_decompress(bytes, bytes.len().saturating_mul(ZSTD_MAX_RATIO))
+    } else {
+        Err(DecodeFailed(format!("unknown compression {id}")))
+    }
+}
+
+/// Decompress zstd `bytes`, fail if the value is larger than `max_len`
+#[cfg(feature = "compression")]
+fn zstd_decompress(bytes: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let decoder = zstd::Decoder::new(bytes).map_err(|e| DecodeFailed(format!("zstd: {e}")))?;
+    let mut value = vec![];
+    decoder
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut value)
+        .map_err(|e| DecodeFailed(format!("zstd: {e}")))?;
+    if value.len() > max_len {
+        return Err(DecodeFailed(format!(
+            "zstd: the value exceeds {max_len} bytes"
+        )));
+    }
+    Ok(value)
+}
+
+/// Decompress the value compressed with the algorithm of `id`
+#[cfg(not(feature = "compression"))]
+pub fn decompress(id: i32, _: &[u8]) -> Result<Vec<u8>> {
+    Err(DecodeFailed(format!(
+        "value is compressed with {id}, which requires feature \"compression\""
+    )))
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use crate::Error::{DataInvalid, DecodeFailed};
+    use crate::core::compression::{Compression, decompress, zstd_decompress};
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let value = "json".repeat(256).into_bytes();
+        for algorithm in [Compression::Lz4, Compression::Zstd] {
+            let compressed = algorithm.compress(&value).unwrap();
+            assert!(compressed.len() < value.len());
+            assert_eq!(decompress(algorithm.id(), &compressed), Ok(value.clone()));
+        }
+        // The prepended size exceeds the max ratio
+        let mut malformed = u32::MAX.to_le_bytes().to_vec();
+        malformed.push(0);
+        assert_eq!(
+            decompress(Compression::Lz4.id(), &malformed),
+            Err(DataInvalid)
+        );
+        assert!(decompress(9, &value).is_err());
+
+        // The zstd value is read up to the limit
+        let compressed = Compression::Zstd.compress(&value).unwrap();
+        assert_eq!(zstd_decompress(&compressed, value.len()), Ok(value.clone()));
+        assert!(matches!(
+            zstd_decompress(&compressed, value.len() - 1),
+            Err(DecodeFailed(_))
+        ));
+        // The most compressible value is within the max ratio
+        let zeros = vec![0; 1 << 20];
+        let compressed = Compression::Zstd.compress(&zeros).unwrap();
+        assert_eq!(decompress(Compression::Zstd.id(), &compressed), Ok(zeros));
+    }
+}
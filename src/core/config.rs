@@ -1,20 +1,194 @@
 use crate::Error::IOError;
+#[cfg(feature = "encryption")]
+use crate::Error::InvalidKey;
 use crate::Result;
-use std::fs;
+#[cfg(feature = "compression")]
+use crate::core::compression::Compression;
+use crate::core::header::Codec;
+use crate::core::recovery::RecoveryStrategy;
+use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
+use std::{fs, io};
 
 const LOG_TAG: &str = "MMKV:Config";
+// The file doubles its size until the step reaches this size, then grows linearly
+const MAX_DOUBLING_STEP: u64 = 32 * 1024 * 1024;
+// The file is shrunk after trim if the content takes less than 1 / SHRINK_RATIO of it
+const SHRINK_RATIO: u64 = 4;
+
+/// The checksum appended to each record, see [MMKVBuilder::checksum](crate::MMKVBuilder::checksum)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Checksum {
+    /// CRC-32C (Castagnoli), 4 bytes per record
+    #[default]
+    Crc32c,
+    /// XXH3 64 bits hash, 8 bytes per record
+    Xxh3,
+}
+
+impl Checksum {
+    pub(crate) fn codec(self) -> Codec {
+        match self {
+            Checksum::Crc32c => Codec::Crc32c,
+            Checksum::Xxh3 => Codec::Xxh3,
+        }
+    }
+}
+
+/// The key of an encrypted file, AES-128 or AES-256 by its len, hidden from the debug output
+#[cfg(feature = "encryption")]
+#[derive(Clone, PartialEq)]
+pub enum Key {
+    Aes128([u8; 16]),
+    Aes256([u8; 32]),
+}
+
+#[cfg(feature = "encryption")]
+impl Key {
+    /// Parse a hexadecimal key of 32 or 64 digits
+    pub fn from_hex(key: &str) -> Result<Self> {
+        // The error of hex may quote the key, don't leak it
+        let bytes = hex::decode(key)
+            .map_err(|_| InvalidKey("the key is not a hexadecimal string".to_string()))?;
+        Key::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(key: &[u8]) -> Result<Self> {
+        if let Ok(key) = key.try_into() {
+            Ok(Key::Aes128(key))
+        } else if let Ok(key) = key.try_into() {
+            Ok(Key::Aes256(key))
+        } else {
+            Err(InvalidKey(format!(
+                "the key is {} bytes, expect 16 or 32 bytes",
+                key.len()
+            )))
+        }
+    }
+}
+
+/// The key `88C51C536176AD8A8EE4A06F62EE897E` of the encrypted files in the tests
+#[cfg(all(test, feature = "encryption"))]
+pub const TEST_KEY: Key = Key::Aes128([
+    0x88, 0xC5, 0x1C, 0x53, 0x61, 0x76, 0xAD, 0x8A, 0x8E, 0xE4, 0xA0, 0x6F, 0x62, 0xEE, 0x89, 0x7E,
+]);
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Tunables of the backing file, see [MMKVBuilder](crate::MMKVBuilder)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// The size of a newly created file
+    pub initial_size: u64,
+    /// The minimal size added to the file each time it runs out of space
+    pub growth: u64,
+    /// Whether to prefault the pages of the mmap with `MAP_POPULATE` and `MADV_WILLNEED`
+    pub populate: bool,
+    /// The checksum of the records in new files, not used by encrypted files
+    pub checksum: Checksum,
+    /// How to load the file if some records are corrupted
+    pub recovery: RecoveryStrategy,
+    /// The algorithm and the min len of the values to compress
+    #[cfg(feature = "compression")]
+    pub compression: Option<(Compression, usize)>,
+    /// Whether the file is shared by multiple processes, which lock it and reload the changes
+    pub multi_process: bool,
+    /// Whether to reload the file if it's replaced or modified by others
+    pub detect_changes: bool,
+    /// Whether the file is only read, it's never created, resized or written
+    pub read_only: bool,
+    /// The key to encrypt the records, the records are protected by the checksum if it's None
+    #[cfg(feature = "encryption")]
+    pub key: Option<Key>,
+}
+
+impl Options {
+    pub fn new(page_size: u64) -> Self {
+        Options {
+            initial_size: page_size,
+            growth: page_size,
+            populate: true,
+            checksum: Checksum::default(),
+            recovery: RecoveryStrategy::default(),
+            #[cfg(feature = "compression")]
+            compression: None,
+            multi_process: false,
+            detect_changes: false,
+            read_only: false,
+            #[cfg(feature = "encryption")]
+            key: None,
+        }
+    }
+
+    /// The files of the tests are encrypted with feature "encryption"
+    #[cfg(test)]
+    pub fn for_test(page_size: u64) -> Self {
+        Options {
+            #[cfg(feature = "encryption")]
+            key: Some(TEST_KEY),
+            ..Options::new(page_size)
+        }
+    }
+
+    /// The codec of the records in new files
+    pub fn codec(&self) -> Codec {
+        #[cfg(feature = "encryption")]
+        if self.key.is_some() {
+            return Codec::AesEax;
+        }
+        self.checksum.codec()
+    }
+}
 
 pub struct Config {
-    page_size: u64,
+    options: Options,
     pub path: PathBuf,
     pub file: File,
 }
 
 impl Config {
+    #[cfg(test)]
     pub fn new(path: &Path, page_size: u64) -> Result<Self> {
+        Config::with_options(path, Options::for_test(page_size))
+    }
+
+    pub fn with_options(path: &Path, options: Options) -> Result<Self> {
+        let initial_size = options.initial_size;
+        if options.read_only {
+            // An interrupted compaction is left to the writers
+            return Config::open(path, options, initial_size);
+        }
+        if !options.multi_process {
+            Config::recover_compaction(path)?;
+            return Config::open(path, options, initial_size);
+        }
+        // The temp files may belong to a compaction of another process,
+        // which holds the lock until it's finished
+        let mut config = Config::open(path, options, initial_size)?;
+        let _lock = config.lock(true)?;
+        Config::recover_compaction(path)?;
+        Ok(config)
+    }
+
+    fn open(path: &Path, options: Options, initial_size: u64) -> Result<Self> {
+        if options.read_only {
+            let file = File::open(path)
+                .map_err(|e| IOError(format!("failed to open {}: {e}", path.display())))?;
+            return Ok(Config {
+                options,
+                path: path.to_path_buf(),
+                file,
+            });
+        }
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -32,7 +206,7 @@ impl Config {
             })?
             .len();
         if file_len == 0 {
-            file_len += page_size;
+            file_len += initial_size;
             file.set_len(file_len).map_err(|e| {
                 IOError(format!(
                     "failed to initialize file size for {} to {}: {e}",
@@ -44,24 +218,135 @@ impl Config {
         file.sync_all()
             .map_err(|e| IOError(format!("failed to sync {}: {e}", path.display())))?;
         Ok(Config {
-            page_size,
+            options,
             path: path.to_path_buf(),
             file,
         })
     }
 
-    pub fn expand(&mut self) -> Result<()> {
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Finish or discard a compaction which was interrupted by a crash, see [Config::replace]
+    fn recover_compaction(path: &Path) -> Result<()> {
+        let temp_path = temp_file_path(path);
+        let temp_meta_path = meta_file_path(&temp_path);
+        if temp_path.exists() {
+            warn!(
+                LOG_TAG,
+                "discard interrupted compaction of {}",
+                path.display()
+            );
+            // Remove the meta file first, a meta file without data file means committed
+            remove_file_if_exists(&temp_meta_path)?;
+            remove_file_if_exists(&temp_path)
+        } else if temp_meta_path.exists() {
+            warn!(
+                LOG_TAG,
+                "finish interrupted compaction of {}",
+                path.display()
+            );
+            rename(&temp_meta_path, &meta_file_path(path))?;
+            sync_parent_dir(path)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Create an empty temp file beside this file with the same size,
+    /// which replaces this file by [Config::replace] once fully written
+    pub fn create_temp(&self) -> Result<Config> {
+        let temp_path = temp_file_path(&self.path);
+        remove_file_if_exists(&meta_file_path(&temp_path))?;
+        remove_file_if_exists(&temp_path)?;
+        Config::open(&temp_path, self.options.clone(), self.file_size()?)
+    }
+
+    /**
+    Atomically replace this file with the `temp` file created by [Config::create_temp],
+    the meta file of `temp` if exists is moved after the data file.
+
+    The rename of the data file is the commit point, if the process crashes before it,
+    the temp files are discarded when opening, if it crashes after it,
+    the left meta file is moved when opening, see [Config::recover_compaction].
+    */
+    pub fn replace(&mut self, temp: Config) -> Result<()> {
+        // Other processes opening the new file wait until its meta file is moved as well
+        let _lock = FileLock::new(&temp.file, true)?;
+        temp.file
+            .sync_all()
+            .map_err(|e| IOError(format!("failed to sync {}: {e}", temp.path.display())))?;
+        rename(&temp.path, &self.path)?;
+        let temp_meta_path = meta_file_path(&temp.path);
+        if temp_meta_path.exists() {
+            rename(&temp_meta_path, &meta_file_path(&self.path))?;
+        }
+        sync_parent_dir(&self.path)?;
+        self.file = temp.file;
+        Ok(())
+    }
+
+    /**
+    Lock the file shared by multiple processes, see [Options::multi_process].
+
+    The lock is taken on the opened file, if another process has replaced or removed the file
+    at the path meanwhile, the file at the path is opened and locked instead.
+    Return the lock and whether the file is reopened, the caller must reload the file if so.
+    */
+    pub fn lock(&mut self, exclusive: bool) -> Result<(FileLock, bool)> {
+        let mut reopened = false;
+        loop {
+            let lock = FileLock::new(&self.file, exclusive)?;
+            if !self.is_replaced()? {
+                return Ok((lock, reopened));
+            }
+            drop(lock);
+            info!(LOG_TAG, "{} is replaced, reopen it", self.path.display());
+            self.reopen_file()?;
+            reopened = true;
+        }
+    }
+
+    /// Open the file at the path again, which may have been replaced or removed by others
+    pub fn reopen_file(&mut self) -> Result<()> {
+        self.file = Config::open(&self.path, self.options.clone(), self.options.initial_size)?.file;
+        Ok(())
+    }
+
+    /// Whether the opened file is no longer the file at the path
+    pub fn is_replaced(&self) -> Result<bool> {
+        let opened = self.file.metadata().map_err(|e| {
+            IOError(format!(
+                "failed to get metadata for {}: {e}",
+                self.path.display()
+            ))
+        })?;
+        match fs::metadata(&self.path) {
+            Ok(current) => Ok(current.ino() != opened.ino() || current.dev() != opened.dev()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(IOError(format!(
+                "failed to get metadata for {}: {e}",
+                self.path.display()
+            ))),
+        }
+    }
+
+    /// Expand the file to hold at least `required_size` bytes with a single resize
+    pub fn expand(&mut self, required_size: u64) -> Result<()> {
         let expand_start = Instant::now();
         let file_size = self.file_size()?;
-        info!(LOG_TAG, "start expand, file size: {}", file_size);
-        // expand the file size with page_size
+        info!(
+            LOG_TAG,
+            "start expand, file size: {}, required size: {}", file_size, required_size
+        );
         self.file.sync_all().map_err(|e| {
             IOError(format!(
                 "failed to sync file before expand {}: {e}",
                 self.path.display()
             ))
         })?;
-        let expanded_size = file_size + self.page_size;
+        let expanded_size = self.grow_size(file_size, required_size);
         self.file.set_len(expanded_size).map_err(|e| {
             IOError(format!(
                 "failed to expand file {} to {}: {e}",
@@ -78,6 +363,40 @@ impl Config {
         Ok(())
     }
 
+    /// Shrink the file toward `content_len` if it's mostly empty, return whether it's shrunk,
+    /// the caller must remap the file after shrinking
+    pub fn shrink(&mut self, content_len: u64) -> Result<bool> {
+        let file_size = self.file_size()?;
+        if file_size <= self.options.initial_size || content_len > file_size / SHRINK_RATIO {
+            return Ok(false);
+        }
+        // Keep the same headroom as the content, so the next writes don't expand it at once
+        let shrunk_size = self.grow_size(self.options.initial_size, content_len.saturating_mul(2));
+        if shrunk_size >= file_size {
+            return Ok(false);
+        }
+        self.file.set_len(shrunk_size).map_err(|e| {
+            IOError(format!(
+                "failed to shrink file {} to {}: {e}",
+                self.path.display(),
+                shrunk_size
+            ))
+        })?;
+        info!(
+            LOG_TAG,
+            "shrunk, file size: {} -> {}, content len: {}", file_size, shrunk_size, content_len
+        );
+        Ok(true)
+    }
+
+    fn grow_size(&self, mut size: u64, required_size: u64) -> u64 {
+        while size < required_size {
+            let step = size.min(MAX_DOUBLING_STEP).max(self.options.growth).max(1);
+            size = size.saturating_add(step);
+        }
+        size
+    }
+
     pub fn file_size(&self) -> Result<u64> {
         self.file
             .metadata()
@@ -91,27 +410,184 @@ impl Config {
     }
 
     pub fn remove_file(&self) -> Result<()> {
-        match fs::remove_file(&self.path) {
-            Ok(()) => Ok(()),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-            Err(e) => Err(IOError(format!(
-                "failed to remove {}: {e}",
-                self.path.display()
-            ))),
-        }
+        remove_file_if_exists(&self.path)
     }
 
+    /// Open the file at the same path again, which may have been replaced after compaction
     #[cfg(test)]
-    pub fn try_clone(&self) -> Result<Self> {
-        Ok(Config {
-            page_size: self.page_size,
-            path: self.path.clone(),
-            file: self.file.try_clone().map_err(|e| {
-                IOError(format!(
-                    "failed to clone config file {}: {e}",
-                    self.path.display()
-                ))
-            })?,
-        })
+    pub fn reopen(&self) -> Result<Self> {
+        Config::with_options(&self.path, self.options.clone())
+    }
+}
+
+/// An advisory `flock` of the file, released when dropped
+pub struct FileLock(File);
+
+impl FileLock {
+    fn new(file: &File, exclusive: bool) -> Result<Self> {
+        // Lock with a duplicated descriptor, which unlocks the same file even if it's replaced
+        let file = file
+            .try_clone()
+            .map_err(|e| IOError(format!("failed to duplicate file descriptor: {e}")))?;
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(FileLock(file));
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(IOError(format!("failed to lock file: {e}")));
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.0.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+/// The path of the file which stores the nonce of the encrypted file at `path`
+pub fn meta_file_path(path: &Path) -> PathBuf {
+    let meta_ext = match path.extension() {
+        Some(ext) => format!("{}.meta", ext.to_string_lossy()),
+        None => "meta".to_string(),
+    };
+    path.with_extension(meta_ext)
+}
+
+fn temp_file_path(path: &Path) -> PathBuf {
+    let mut temp_path = OsString::from(path);
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(IOError(format!("failed to remove {}: {e}", path.display()))),
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to).map_err(|e| {
+        IOError(format!(
+            "failed to rename {} to {}: {e}",
+            from.display(),
+            to.display()
+        ))
+    })
+}
+
+// Persist the renames in the directory
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| IOError(format!("failed to sync dir {}: {e}", dir.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::fd::AsRawFd;
+    use std::path::Path;
+
+    use super::{Config, meta_file_path, temp_file_path};
+
+    fn remove_all(path: &Path) {
+        let temp_path = temp_file_path(path);
+        for path in [
+            path,
+            &meta_file_path(path),
+            &temp_path,
+            &meta_file_path(&temp_path),
+        ] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_recover_compaction() {
+        let path = Path::new("test_recover_compaction");
+        let temp_path = temp_file_path(path);
+        remove_all(path);
+        fs::write(path, [1u8; 16]).unwrap();
+        fs::write(meta_file_path(path), [1u8; 11]).unwrap();
+
+        // Interrupted before the rename of data file, the temp files are discarded
+        fs::write(&temp_path, [2u8; 16]).unwrap();
+        fs::write(meta_file_path(&temp_path), [2u8; 11]).unwrap();
+        let config = Config::new(path, 64).unwrap();
+        assert!(!temp_path.exists());
+        assert!(!meta_file_path(&temp_path).exists());
+        assert_eq!(fs::read(path).unwrap(), [1u8; 16]);
+        assert_eq!(fs::read(meta_file_path(path)).unwrap(), [1u8; 11]);
+        drop(config);
+
+        // Interrupted after the rename of data file, the meta file is moved
+        fs::write(meta_file_path(&temp_path), [2u8; 11]).unwrap();
+        let config = Config::new(path, 64).unwrap();
+        assert!(!meta_file_path(&temp_path).exists());
+        assert_eq!(fs::read(meta_file_path(path)).unwrap(), [2u8; 11]);
+        drop(config);
+        remove_all(path);
+    }
+
+    #[test]
+    fn test_lock_replaced_file() {
+        let path = Path::new("test_lock_replaced_file");
+        remove_all(path);
+        let mut config = Config::new(path, 64).unwrap();
+        let mut other = config.reopen().unwrap();
+        let try_lock = |config: &Config| unsafe {
+            libc::flock(config.file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) == 0
+        };
+        let (lock, reopened) = config.lock(true).unwrap();
+        assert!(!reopened);
+        // The file opened by another process can't be locked meanwhile
+        assert!(!try_lock(&other));
+        drop(lock);
+        assert!(try_lock(&other));
+        unsafe { libc::flock(other.file.as_raw_fd(), libc::LOCK_UN) };
+
+        // Replaced by another process, the new file is opened
+        let temp = other.create_temp().unwrap();
+        other.replace(temp).unwrap();
+        let (_, reopened) = config.lock(false).unwrap();
+        assert!(reopened);
+        assert!(!config.lock(false).unwrap().1);
+        fs::remove_file(path).unwrap();
+        assert!(config.lock(false).unwrap().1);
+        assert!(path.exists());
+        remove_all(path);
+    }
+
+    #[test]
+    fn test_replace_with_temp() {
+        let path = Path::new("test_replace_with_temp");
+        remove_all(path);
+        let mut config = Config::new(path, 64).unwrap();
+        let temp = config.create_temp().unwrap();
+        assert_eq!(temp.file_size().unwrap(), 64);
+        fs::write(meta_file_path(&temp.path), [2u8; 11]).unwrap();
+        let temp_path = temp.path.clone();
+        config.replace(temp).unwrap();
+        assert!(!temp_path.exists());
+        assert!(!meta_file_path(&temp_path).exists());
+        assert_eq!(fs::read(meta_file_path(path)).unwrap(), [2u8; 11]);
+        assert_eq!(config.file_size().unwrap(), 64);
+        remove_all(path);
     }
 }
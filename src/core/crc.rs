@@ -1,8 +1,11 @@
-use crate::Error::{DataInvalid, DecodeFailed};
+use crate::Error::DecodeFailed;
 use crate::Result;
-use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder};
+use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder, LEN_PREFIX, record_body};
+use crate::core::checksum::{ChecksumEncoderDecoder, codec_pair};
+use crate::core::config::Checksum;
+use crate::core::header::Codec;
 use crc::{CRC_8_AUTOSAR, Crc};
-use std::mem::size_of;
+use std::path::Path;
 
 const LOG_TAG: &str = "MMKV:Crc";
 
@@ -20,16 +23,31 @@ impl Encoder for CrcEncoderDecoder {
         data.push(sum);
         Ok(data)
     }
+
+    fn codec(&self) -> Codec {
+        Codec::Crc8
+    }
+
+    // Only read and append to the legacy files, the new file uses the stronger checksum
+    fn for_new_file(&self, _: &Path, checksum: Checksum) -> Result<Box<dyn Encoder>> {
+        Ok(Box::new(ChecksumEncoderDecoder(checksum)))
+    }
+
+    fn for_existing_file(&self, _: &Path, codec: Codec) -> Result<Box<dyn Encoder>> {
+        codec_pair(codec).map(|(encoder, _)| encoder)
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        Box::new(CrcEncoderDecoder)
+    }
 }
 
 impl Decoder for CrcEncoderDecoder {
     fn decode_bytes(&self, data: &[u8], _: u32) -> Result<DecodeResult> {
-        let offset = size_of::<u32>();
-        let item_len = u32::from_be_bytes(data[0..offset].try_into().map_err(|_| DataInvalid)?);
-        let bytes_to_decode = &data[offset..(offset + item_len as usize - 1)];
-        let read_len = offset as u32 + item_len;
-        let sum = data[offset + item_len as usize - 1];
-        let result = if CRC8.checksum(bytes_to_decode) == sum {
+        let body = record_body(data, 1)?;
+        let read_len = (LEN_PREFIX + body.len()) as u32;
+        let (bytes_to_decode, sum) = body.split_at(body.len() - 1);
+        let result = if CRC8.checksum(bytes_to_decode) == sum[0] {
             Buffer::from_encoded_bytes(bytes_to_decode)
         } else {
             Err(DecodeFailed("CRC check failed".to_string()))
@@ -50,6 +68,7 @@ impl Decoder for CrcEncoderDecoder {
 
 #[cfg(test)]
 mod tests {
+    use crate::Error::DataInvalid;
     use crate::core::buffer::{Buffer, Decoder, Encoder};
     use crate::core::crc::CrcEncoderDecoder;
 
@@ -60,5 +79,13 @@ mod tests {
         let decode_result = CrcEncoderDecoder.decode_bytes(bytes.as_slice(), 0).unwrap();
         assert_eq!(decode_result.len, bytes.len() as u32);
         assert_eq!(decode_result.buffer, Some(buffer));
+
+        // Truncated, zero or out of bounds length
+        for bytes in [&bytes[..2], &[0, 0, 0, 0, 1], &bytes[..bytes.len() - 1]] {
+            assert!(matches!(
+                CrcEncoderDecoder.decode_bytes(bytes, 0),
+                Err(DataInvalid)
+            ));
+        }
     }
 }
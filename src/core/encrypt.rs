@@ -1,107 +1,236 @@
-use aes::Aes128;
+use aes::{Aes128, Aes256};
 use eax::Eax;
 use eax::aead::consts::U8;
 use eax::aead::rand_core::RngCore;
 use eax::aead::stream::{NewStream, StreamBE32, StreamPrimitive};
 use eax::aead::{KeyInit, OsRng, Payload, generic_array::GenericArray};
-use std::fs;
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
-use std::mem::size_of;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::Error::{DataInvalid, DecryptFailed, EncryptFailed};
+use crate::Error::{DataInvalid, DecryptFailed, EncryptFailed, IOError, WrongKey};
 use crate::Result;
-use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder};
+use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder, LEN_PREFIX, record_body};
+use crate::core::config::{Checksum, Key, meta_file_path};
+use crate::core::header::Codec;
 
 const LOG_TAG: &str = "MMKV:Encrypt";
-const NONCE_LEN: usize = 11;
+pub(crate) const NONCE_LEN: usize = 11;
+/// The meta file holds the nonce followed by the key check, see [StreamWrapper::key_check]
+pub(crate) const KEY_CHECK_LEN: usize = 8;
 
 type Aes128Eax = Eax<Aes128, U8>;
+type Aes256Eax = Eax<Aes256, U8>;
+/// The counter of the stream is the same for both key sizes
 type Stream = StreamBE32<Aes128Eax>;
 
 #[derive(Clone)]
 pub struct Encryptor {
     pub meta_file_path: PathBuf,
+    key: Key,
     encryptor: Arc<StreamWrapper>,
+    /// Whether the key is checked with the meta file, the meta files written by the older
+    /// versions only have the nonce
+    key_checked: bool,
 }
 
-#[repr(transparent)]
-struct StreamWrapper(Stream);
+enum StreamWrapper {
+    Aes128(StreamBE32<Aes128Eax>),
+    Aes256(StreamBE32<Aes256Eax>),
+}
 
 impl Encryptor {
-    pub fn init(file_path: &Path, key: &str) -> Self {
-        let decoded_key = hex::decode(key).unwrap();
-        let meta_file_path = Encryptor::resolve_meta_file_path(file_path);
-        let encryptor =
-            StreamWrapper::init(decoded_key.as_slice().try_into().unwrap(), &meta_file_path);
-        Encryptor {
+    /// Fails with [WrongKey](crate::Error::WrongKey) if the file is encrypted with another key,
+    /// or if the nonce of a new file can't be written to its meta file
+    pub fn init(file_path: &Path, key: &Key) -> Result<Self> {
+        let meta_file_path = meta_file_path(file_path);
+        let (encryptor, key_checked) = StreamWrapper::init(key, &meta_file_path)?;
+        Ok(Encryptor {
             meta_file_path,
+            key: key.clone(),
             encryptor: Arc::new(encryptor),
+            key_checked,
+        })
+    }
+
+    /// An encryptor of the same file with another `key`, see [Encryptor::ephemeral]
+    pub fn with_key(&self, key: &Key) -> Self {
+        Encryptor::ephemeral(self.meta_file_path.clone(), key)
+    }
+
+    /// An encryptor whose nonce is only kept in memory, it's used to create the encoder
+    /// of the rewritten file by [Encoder::for_new_file]
+    pub fn ephemeral(meta_file_path: PathBuf, key: &Key) -> Self {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        Encryptor {
+            meta_file_path,
+            key: key.clone(),
+            encryptor: Arc::new(StreamWrapper::with_nonce(key, &nonce)),
+            key_checked: true,
         }
     }
 
-    fn resolve_meta_file_path(path: &Path) -> PathBuf {
-        let meta_ext = match path.extension() {
-            Some(ext) => format!("{}.meta", ext.to_string_lossy()),
-            None => "meta".to_string(),
-        };
-        path.with_extension(meta_ext)
+    pub fn has_key(&self, key: &Key) -> bool {
+        self.key == *key
+    }
+
+    /// Check the key of a meta file without the key check by the `records`, the key is wrong
+    /// if none of them decrypts. The key check is saved if the key decrypts any of them,
+    /// unless the file is `read_only`
+    pub fn check_legacy_key(&mut self, records: &[u8], read_only: bool) -> Result<()> {
+        if self.key_checked {
+            return Ok(());
+        }
+        let mut decrypted = records.is_empty();
+        let mut rejected = false;
+        let (mut offset, mut position) = (0, 0);
+        // The records before the first decrypted one are corrupted if the key is right
+        while offset < records.len() {
+            let Ok(result) = self.decode_bytes(&records[offset..], position) else {
+                break;
+            };
+            if result.buffer.is_some() {
+                decrypted = true;
+                break;
+            }
+            rejected = true;
+            offset += result.len as usize;
+            position += 1;
+        }
+        if !decrypted {
+            if rejected {
+                return Err(WrongKey);
+            }
+            // The length is corrupted, leave it to the recovery and check the key next time
+            return Ok(());
+        }
+        if read_only {
+            return Ok(());
+        }
+        self.save_key_check()
+    }
+
+    /// Append the key check to a meta file which only has the nonce
+    fn save_key_check(&mut self) -> Result<()> {
+        let key_check = self.encryptor.key_check()?;
+        OpenOptions::new()
+            .append(true)
+            .open(&self.meta_file_path)
+            .and_then(|mut meta_file| {
+                meta_file.write_all(&key_check)?;
+                meta_file.sync_all()
+            })
+            .map_err(|e| {
+                IOError(format!(
+                    "failed to write meta file {}: {e}",
+                    self.meta_file_path.display()
+                ))
+            })?;
+        self.key_checked = true;
+        Ok(())
     }
 }
 
 impl StreamWrapper {
-    fn init(key: [u8; 16], meta_file_path: &PathBuf) -> Self {
+    /// The stream of the meta file and whether the key is checked with it
+    fn init(key: &Key, meta_file_path: &PathBuf) -> Result<(Self, bool)> {
         if meta_file_path.exists() {
             StreamWrapper::new_with_nonce(key, meta_file_path)
         } else {
-            StreamWrapper::new(key, meta_file_path)
+            Ok((StreamWrapper::new(key, meta_file_path)?, true))
         }
     }
 
-    fn new(key: [u8; 16], meta_file_path: &PathBuf) -> Self {
-        let generic_array = GenericArray::from_slice(key.as_slice());
-        let mut nonce = GenericArray::default();
+    fn new(key: &Key, meta_file_path: &PathBuf) -> Result<Self> {
+        let mut nonce = [0u8; NONCE_LEN];
         OsRng.fill_bytes(&mut nonce);
-        let mut nonce_file = OpenOptions::new()
+        let stream = StreamWrapper::with_nonce(key, &nonce);
+        let key_check = stream.key_check()?;
+        OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
             .open(meta_file_path)
-            .unwrap();
-        nonce_file
-            .write_all(nonce.as_slice())
-            .expect("failed to write nonce file");
-        let cipher = Aes128Eax::new(generic_array);
-        let stream = StreamBE32::from_aead(cipher, &nonce);
-        StreamWrapper(stream)
-    }
-
-    fn new_with_nonce(key: [u8; 16], meta_file_path: &PathBuf) -> Self {
-        let mut nonce_file = OpenOptions::new().read(true).open(meta_file_path).unwrap();
-        let mut nonce = Vec::<u8>::new();
-        let error_handle = |reason: String| {
-            error!(LOG_TAG, "filed to read nonce, reason: {:?}", reason);
-            warn!(
-                LOG_TAG,
-                "delete meta file due to previous reason, which may cause mmkv drop all encrypted data"
-            );
-            let _ = fs::remove_file(meta_file_path);
-            StreamWrapper::new(key, meta_file_path)
-        };
-        match nonce_file.read_to_end(&mut nonce) {
-            Ok(len) if len != NONCE_LEN => {
-                return error_handle("meta file corruption".to_string());
+            .and_then(|mut nonce_file| {
+                nonce_file.write_all(nonce.as_slice())?;
+                nonce_file.write_all(key_check.as_slice())?;
+                nonce_file.sync_all()
+            })
+            .map_err(|e| {
+                IOError(format!(
+                    "failed to write meta file {}: {e}",
+                    meta_file_path.display()
+                ))
+            })?;
+        Ok(stream)
+    }
+
+    fn new_with_nonce(key: &Key, meta_file_path: &PathBuf) -> Result<(Self, bool)> {
+        let mut nonce_file = OpenOptions::new()
+            .read(true)
+            .open(meta_file_path)
+            .map_err(|e| {
+                IOError(format!(
+                    "failed to open meta file {}: {e}",
+                    meta_file_path.display()
+                ))
+            })?;
+        // The data can't be decrypted without the nonce, never replace the meta file
+        let mut meta = Vec::<u8>::new();
+        match nonce_file.read_to_end(&mut meta) {
+            Ok(len) if len != NONCE_LEN && len != NONCE_LEN + KEY_CHECK_LEN => {
+                error!(
+                    LOG_TAG,
+                    "meta file {} is corrupted, len {}",
+                    meta_file_path.display(),
+                    len
+                );
+                return Err(DataInvalid);
+            }
+            Err(e) => {
+                return Err(IOError(format!(
+                    "failed to read meta file {}: {e}",
+                    meta_file_path.display()
+                )));
             }
-            Err(e) => return error_handle(format!("{:?}", e)),
             _ => {}
         }
-        let generic_array = GenericArray::from_slice(&key);
-        let nonce = GenericArray::from_slice(nonce.as_slice());
-        let cipher = Aes128Eax::new(generic_array);
-        let stream = StreamBE32::from_aead(cipher, nonce);
-        StreamWrapper(stream)
+        let (nonce, key_check) = meta.split_at(NONCE_LEN);
+        let stream = StreamWrapper::with_nonce(key, nonce);
+        if key_check.is_empty() {
+            return Ok((stream, false));
+        }
+        if stream.key_check()? != key_check {
+            return Err(WrongKey);
+        }
+        Ok((stream, true))
+    }
+
+    fn with_nonce(key: &Key, nonce: &[u8]) -> Self {
+        let nonce = GenericArray::from_slice(nonce);
+        match key {
+            Key::Aes128(key) => {
+                let cipher = Aes128Eax::new(GenericArray::from_slice(key));
+                StreamWrapper::Aes128(StreamBE32::from_aead(cipher, nonce))
+            }
+            Key::Aes256(key) => {
+                let cipher = Aes256Eax::new(GenericArray::from_slice(key));
+                StreamWrapper::Aes256(StreamBE32::from_aead(cipher, nonce))
+            }
+        }
+    }
+
+    /// The tag of an empty last block at position 0, which is never used by the records,
+    /// so it only depends on the key and the nonce
+    fn key_check(&self) -> Result<Vec<u8>> {
+        let payload = Payload::from(&[][..]);
+        match self {
+            StreamWrapper::Aes128(stream) => stream.encrypt(0, true, payload),
+            StreamWrapper::Aes256(stream) => stream.encrypt(0, true, payload),
+        }
+        .map_err(|e| EncryptFailed(e.to_string()))
     }
 
     fn encrypt(&self, bytes: Vec<u8>, position: u32) -> Result<Vec<u8>> {
@@ -109,10 +238,12 @@ impl StreamWrapper {
             return Err(EncryptFailed(String::from("counter overflow")));
         }
 
-        let result = self
-            .0
-            .encrypt(position, false, Payload::from(bytes.as_slice()))
-            .map_err(|e| EncryptFailed(e.to_string()))?;
+        let payload = Payload::from(bytes.as_slice());
+        let result = match self {
+            StreamWrapper::Aes128(stream) => stream.encrypt(position, false, payload),
+            StreamWrapper::Aes256(stream) => stream.encrypt(position, false, payload),
+        }
+        .map_err(|e| EncryptFailed(e.to_string()))?;
 
         Ok(result)
     }
@@ -122,10 +253,12 @@ impl StreamWrapper {
             return Err(DecryptFailed(String::from("counter overflow")));
         }
 
-        let result = self
-            .0
-            .decrypt(position, false, Payload::from(bytes.as_slice()))
-            .map_err(|e| DecryptFailed(e.to_string()))?;
+        let payload = Payload::from(bytes.as_slice());
+        let result = match self {
+            StreamWrapper::Aes128(stream) => stream.decrypt(position, false, payload),
+            StreamWrapper::Aes256(stream) => stream.decrypt(position, false, payload),
+        }
+        .map_err(|e| DecryptFailed(e.to_string()))?;
 
         Ok(result)
     }
@@ -140,15 +273,44 @@ impl Encoder for Encryptor {
         data.extend_from_slice(crypt_bytes.as_slice());
         Ok(data)
     }
+
+    fn codec(&self) -> Codec {
+        Codec::AesEax
+    }
+
+    fn for_new_file(&self, path: &Path, _: Checksum) -> Result<Box<dyn Encoder>> {
+        // Never reuse the nonce, the positions restart from 0 in the new file
+        let meta_file_path = meta_file_path(path);
+        let encryptor = StreamWrapper::new(&self.key, &meta_file_path)?;
+        Ok(Box::new(Encryptor {
+            meta_file_path,
+            key: self.key.clone(),
+            encryptor: Arc::new(encryptor),
+            key_checked: true,
+        }))
+    }
+
+    fn for_existing_file(&self, path: &Path, _: Codec) -> Result<Box<dyn Encoder>> {
+        // The nonce of the file is written by the process which created it
+        let meta_file_path = meta_file_path(path);
+        let (encryptor, key_checked) = StreamWrapper::init(&self.key, &meta_file_path)?;
+        Ok(Box::new(Encryptor {
+            meta_file_path,
+            key: self.key.clone(),
+            encryptor: Arc::new(encryptor),
+            key_checked,
+        }))
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        Box::new(self.clone())
+    }
 }
 
 impl Decoder for Encryptor {
     fn decode_bytes(&self, data: &[u8], position: u32) -> Result<DecodeResult> {
-        let data_offset = size_of::<u32>();
-        let item_len =
-            u32::from_be_bytes(data[0..data_offset].try_into().map_err(|_| DataInvalid)?);
-        let bytes_to_decode = &data[data_offset..(data_offset + item_len as usize)];
-        let read_len = data_offset as u32 + item_len;
+        let bytes_to_decode = record_body(data, 1)?;
+        let read_len = (LEN_PREFIX + bytes_to_decode.len()) as u32;
         let result = self
             .encryptor
             .decrypt(bytes_to_decode.to_vec(), position)
@@ -169,17 +331,17 @@ impl Decoder for Encryptor {
 
 #[cfg(test)]
 mod tests {
+    use crate::Error::{DataInvalid, InvalidKey, WrongKey};
     use crate::core::buffer::{Buffer, Decoder, Encoder};
-    use crate::core::encrypt::Encryptor;
+    use crate::core::config::{Key, TEST_KEY};
+    use crate::core::encrypt::{Encryptor, KEY_CHECK_LEN, NONCE_LEN};
     use std::fs;
     use std::path::Path;
 
-    const TEST_KEY: &str = "88C51C536176AD8A8EE4A06F62EE897E";
-
     #[test]
     fn test_crypt_buffer() {
         let path = Path::new("./mmkv");
-        let encryptor = Encryptor::init(path, TEST_KEY);
+        let encryptor = Encryptor::init(path, &TEST_KEY).unwrap();
         let buffer1 = Buffer::new("key1", 1);
         let bytes1 = encryptor.encode_to_bytes(&buffer1, 0).unwrap();
         let decode_result1 = encryptor.decode_bytes(bytes1.as_slice(), 0).unwrap();
@@ -197,9 +359,98 @@ mod tests {
                 .buffer
                 .is_none()
         );
-        let encryptor = Encryptor::init(path, TEST_KEY);
+        let encryptor = Encryptor::init(path, &TEST_KEY).unwrap();
         let new_decode_result1 = encryptor.decode_bytes(bytes1.as_slice(), 0).unwrap();
         assert_eq!(new_decode_result1.buffer, Some(buffer1));
+        // Truncated, zero or out of bounds length
+        for bytes in [&bytes1[..2], &[0, 0, 0, 0], &bytes1[..bytes1.len() - 1]] {
+            assert!(matches!(encryptor.decode_bytes(bytes, 0), Err(DataInvalid)));
+        }
+        let _ = fs::remove_file(&encryptor.meta_file_path);
+    }
+
+    #[test]
+    fn test_key_sizes() {
+        assert_eq!(
+            Key::from_hex("88C51C536176AD8A8EE4A06F62EE897E"),
+            Ok(TEST_KEY)
+        );
+        for key in [
+            "88C51C536176AD8A8EE4A06F62EE89",
+            "88C51C536176AD8A8EE4A06F62EE897G",
+        ] {
+            assert!(matches!(Key::from_hex(key), Err(InvalidKey(_))));
+        }
+        assert!(matches!(Key::from_bytes(&[0; 24]), Err(InvalidKey(_))));
+
+        let path = Path::new("./test_key_sizes");
+        let aes256 = Encryptor::init(path, &Key::from_bytes(&[7; 32]).unwrap()).unwrap();
+        let buffer = Buffer::new("key", 1);
+        let bytes = aes256.encode_to_bytes(&buffer, 0).unwrap();
+        let decode_result = aes256.decode_bytes(bytes.as_slice(), 0).unwrap();
+        assert_eq!(decode_result.buffer, Some(buffer));
+        // The key of another size is rejected by the key check
+        let aes128 = aes256.with_key(&Key::Aes128([7; 16]));
+        assert!(!aes128.has_key(&Key::Aes256([7; 32])));
+        assert!(matches!(
+            Encryptor::init(path, &Key::Aes128([7; 16])),
+            Err(WrongKey)
+        ));
+        let _ = fs::remove_file(&aes256.meta_file_path);
+    }
+
+    #[test]
+    fn test_key_check() {
+        let path = Path::new("./test_key_check");
+        let wrong_key = Key::Aes128([7; 16]);
+        let _ = fs::remove_file("./test_key_check.meta");
+        let encryptor = Encryptor::init(path, &TEST_KEY).unwrap();
+        assert!(encryptor.key_checked);
+        let meta = fs::read(&encryptor.meta_file_path).unwrap();
+        assert_eq!(meta.len(), NONCE_LEN + KEY_CHECK_LEN);
+        assert!(matches!(Encryptor::init(path, &wrong_key), Err(WrongKey)));
+        let records = encryptor
+            .encode_to_bytes(&Buffer::new("key", 1), 0)
+            .unwrap();
+
+        // The meta files of the older versions only have the nonce
+        fs::write(&encryptor.meta_file_path, &meta[..NONCE_LEN]).unwrap();
+        let mut unchecked = Encryptor::init(path, &wrong_key).unwrap();
+        assert!(!unchecked.key_checked);
+        assert!(matches!(
+            unchecked.check_legacy_key(&records, false),
+            Err(WrongKey)
+        ));
+        assert_eq!(
+            fs::read(&encryptor.meta_file_path).unwrap().len(),
+            NONCE_LEN
+        );
+        let mut legacy = Encryptor::init(path, &TEST_KEY).unwrap();
+        legacy.check_legacy_key(&records, true).unwrap();
+        assert!(!legacy.key_checked);
+        legacy.check_legacy_key(&records, false).unwrap();
+        assert!(legacy.key_checked);
+        assert_eq!(fs::read(&encryptor.meta_file_path).unwrap(), meta);
+        assert!(matches!(Encryptor::init(path, &wrong_key), Err(WrongKey)));
+
+        // The key is right if any record decrypts, even if the first one is corrupted
+        fs::write(&encryptor.meta_file_path, &meta[..NONCE_LEN]).unwrap();
+        let mut corrupted = records.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        corrupted.extend(
+            encryptor
+                .encode_to_bytes(&Buffer::new("key2", 2), 1)
+                .unwrap(),
+        );
+        let mut unchecked = Encryptor::init(path, &wrong_key).unwrap();
+        assert!(matches!(
+            unchecked.check_legacy_key(&corrupted, false),
+            Err(WrongKey)
+        ));
+        let mut legacy = Encryptor::init(path, &TEST_KEY).unwrap();
+        legacy.check_legacy_key(&corrupted, false).unwrap();
+        assert!(legacy.key_checked);
+        assert_eq!(fs::read(&encryptor.meta_file_path).unwrap(), meta);
         let _ = fs::remove_file(&encryptor.meta_file_path);
     }
 }
@@ -0,0 +1,112 @@
+use crate::Result;
+use crate::core::buffer::{Buffer, FromBytes, ProvideTypeToken, ValueType};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::hash_map::IntoIter;
+use std::time::SystemTime;
+
+/// A stored value read from [MMKV::iter](crate::MMKV::iter), which keeps its type info,
+/// so the value can be inspected without knowing the schema up front.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry(Buffer);
+
+impl Entry {
+    pub(crate) fn new(buffer: Buffer) -> Self {
+        Entry(buffer)
+    }
+
+    pub fn key(&self) -> &str {
+        self.0.key()
+    }
+
+    /// The type of the stored value
+    pub fn value_type(&self) -> ValueType {
+        self.0.value_type()
+    }
+
+    /// Returns `true` if the stored value has the same type token with `T`
+    pub fn is<T: ProvideTypeToken>(&self) -> bool {
+        self.0.is_type::<T>()
+    }
+
+    /// The time when the value expires, `None` if it never expires,
+    /// see [MMKV::put_with_ttl](crate::MMKV::put_with_ttl)
+    pub fn expire_at(&self) -> Option<SystemTime> {
+        self.0.expire_at()
+    }
+
+    /// The raw bytes of the stored value, produced by [ToBytes](crate::ToBytes),
+    /// fails if the value is compressed and can't be decompressed
+    pub fn bytes(&self) -> Result<Cow<'_, [u8]>> {
+        self.0.plain_value()
+    }
+
+    /// Parse the stored value, same as [MMKV::get](crate::MMKV::get)
+    pub fn parse<T: ProvideTypeToken + FromBytes>(&self) -> Result<T> {
+        self.0.parse()
+    }
+}
+
+/// A snapshot iterator over all entries of an instance, see [MMKV::iter](crate::MMKV::iter).
+///
+/// Changes made after the iterator created are not visible to it.
+pub struct Entries(IntoIter<String, Buffer>);
+
+impl Entries {
+    pub fn new(snapshot: HashMap<String, Buffer>) -> Self {
+        Entries(snapshot.into_iter())
+    }
+}
+
+impl Iterator for Entries {
+    type Item = (String, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, buffer)| (key, Entry::new(buffer)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Entries {}
+
+#[cfg(test)]
+mod tests {
+    use crate::Error::TypeMissMatch;
+    use crate::core::buffer::{Buffer, ValueType};
+    use crate::core::entry::Entries;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_entries() {
+        let mut map = HashMap::new();
+        for buffer in [
+            Buffer::new("i32", 1),
+            Buffer::new("str", "value"),
+            Buffer::new("bool", true),
+            Buffer::new("i64_array", vec![1i64, 2].as_slice()),
+        ] {
+            map.insert(buffer.key().to_string(), buffer);
+        }
+        let entries = Entries::new(map);
+        assert_eq!(entries.len(), 4);
+        let entries: HashMap<_, _> = entries.collect();
+
+        let entry = &entries["i32"];
+        assert_eq!(entry.key(), "i32");
+        assert_eq!(entry.value_type(), ValueType::I32);
+        assert!(entry.is::<i32>());
+        assert!(!entry.is::<i64>());
+        assert_eq!(entry.bytes().unwrap().as_ref(), 1i32.to_be_bytes());
+        assert_eq!(entry.parse::<i32>(), Ok(1));
+        assert_eq!(entry.parse::<String>(), Err(TypeMissMatch));
+
+        assert_eq!(entries["str"].value_type(), ValueType::Str);
+        assert_eq!(entries["str"].parse::<String>(), Ok("value".to_string()));
+        assert_eq!(entries["bool"].value_type(), ValueType::Bool);
+        assert_eq!(entries["i64_array"].value_type(), ValueType::I64Array);
+        assert_eq!(entries["i64_array"].parse::<Vec<i64>>(), Ok(vec![1, 2]));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::core::buffer::Buffer;
+use crate::core::observer;
+use crate::core::observer::{Change, Observers};
+use crate::core::shared_state::SharedKvMap;
+use crate::{Error, Result};
+use std::sync::{Arc, RwLock};
+
+const LOG_TAG: &str = "MMKV:Failure";
+
+/// How to deal with a failed background write, see [MMKV::set_write_failure_policy](crate::MMKV::set_write_failure_policy)
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WriteFailurePolicy {
+    /// Keep the change in memory, memory and file may diverge until the next successful trim.
+    #[default]
+    KeepInMemory,
+    /// Revert the change in memory, unless the key has been changed again.
+    /// The observers are notified of the reverted change.
+    Rollback,
+    /// Keep the change in memory, and reject all following writes with the failure,
+    /// until [MMKV::clear_write_error](crate::MMKV::clear_write_error) called.
+    ReadOnly,
+}
+
+type ErrorCallback = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// A change which has been applied to memory, and waiting to be written
+pub struct PendingChange {
+    key: String,
+    written: Buffer,
+    previous: Option<Buffer>,
+}
+
+impl PendingChange {
+    pub fn new(key: &str, written: Buffer, previous: Option<Buffer>) -> Self {
+        PendingChange {
+            key: key.to_string(),
+            written,
+            previous,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    last_error: Option<Error>,
+    policy: WriteFailurePolicy,
+    callback: Option<ErrorCallback>,
+}
+
+/// Records failures of the background writes, shared by the instance and the io thread
+#[derive(Clone, Default)]
+pub struct WriteFailures(Arc<RwLock<State>>);
+
+impl WriteFailures {
+    pub fn set_policy(&self, policy: WriteFailurePolicy) -> Result<()> {
+        self.write_state(|state| state.policy = policy)
+    }
+
+    pub fn set_callback(&self, callback: Option<ErrorCallback>) -> Result<()> {
+        self.write_state(|state| state.callback = callback)
+    }
+
+    pub fn last_error(&self) -> Option<Error> {
+        self.0
+            .read()
+            .ok()
+            .and_then(|state| state.last_error.clone())
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.write_state(|state| state.last_error = None)
+    }
+
+    /// Returns the last failure if writes are rejected by [WriteFailurePolicy::ReadOnly]
+    pub fn check_writable(&self) -> Result<()> {
+        let state = self.0.read().map_err(|e| Error::LockError(e.to_string()))?;
+        match &state.last_error {
+            Some(e) if state.policy == WriteFailurePolicy::ReadOnly => Err(e.clone()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Called in io thread when writing `changes` failed, the `observers` are notified of
+    /// the changes reverted by [WriteFailurePolicy::Rollback]
+    pub fn on_failure(
+        &self,
+        error: &Error,
+        shared_kv: &SharedKvMap,
+        observers: &Observers,
+        changes: Vec<PendingChange>,
+    ) {
+        let (policy, callback) = match self.0.write() {
+            Ok(mut state) => {
+                state.last_error = Some(error.clone());
+                (state.policy, state.callback.clone())
+            }
+            Err(e) => {
+                error!(LOG_TAG, "failed to record write failure: {:?}", e);
+                return;
+            }
+        };
+        if policy == WriteFailurePolicy::Rollback {
+            let mut reverted = vec![];
+            match shared_kv.write() {
+                Ok(mut kv_map) => {
+                    for change in changes {
+                        let current = kv_map.get(&change.key);
+                        let unchanged = if change.written.is_deleting() {
+                            current.is_none()
+                        } else {
+                            current == Some(&change.written)
+                        };
+                        if !unchanged {
+                            continue;
+                        }
+                        match &change.previous {
+                            Some(previous) => kv_map.insert(change.key.clone(), previous.clone()),
+                            None => kv_map.remove(&change.key),
+                        };
+                        let written = (!change.written.is_deleting()).then_some(change.written);
+                        reverted.push(Change::new(&change.key, written, change.previous));
+                    }
+                }
+                Err(e) => error!(LOG_TAG, "failed to rollback: {:?}", e),
+            }
+            if !reverted.is_empty() {
+                observers.notify(reverted);
+            }
+        }
+        if let Some(callback) = callback {
+            let error = error.clone();
+            observer::dispatch(move || callback(&error));
+        }
+    }
+
+    fn write_state<F: FnOnce(&mut State)>(&self, f: F) -> Result<()> {
+        let mut state = self
+            .0
+            .write()
+            .map_err(|e| Error::LockError(e.to_string()))?;
+        f(&mut state);
+        Ok(())
+    }
+}
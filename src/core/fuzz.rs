@@ -0,0 +1,69 @@
+//! Entry points of the fuzz targets in `fuzz/`, only built with `--cfg fuzzing`
+use crate::core::buffer::Decoder;
+use crate::core::checksum::codec_pair;
+#[cfg(feature = "encryption")]
+use crate::core::config::Key;
+#[cfg(feature = "encryption")]
+use crate::core::encrypt::Encryptor;
+use crate::core::header::Codec;
+use crate::core::memory_map::MemoryMap;
+use crate::core::recovery::RecoveryStrategy;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+#[cfg(feature = "encryption")]
+const FUZZ_KEY: &str = "88C51C536176AD8A8EE4A06F62EE897E";
+
+fn fuzz_file_path() -> PathBuf {
+    env::temp_dir().join(format!("mmkv_fuzz_{}", process::id()))
+}
+
+fn decoder(codec: Codec) -> Option<Box<dyn Decoder>> {
+    #[cfg(feature = "encryption")]
+    if codec.is_encrypted() {
+        let key = Key::from_hex(FUZZ_KEY).ok()?;
+        let encryptor = Encryptor::init(&fuzz_file_path(), &key).ok()?;
+        return Some(Box::new(encryptor));
+    }
+    codec_pair(codec).ok().map(|(_, decoder)| decoder)
+}
+
+/// Decode `data` as a record with every codec, it must not panic
+pub fn decode_bytes(data: &[u8]) {
+    for codec in [Codec::Crc8, Codec::AesEax, Codec::Crc32c, Codec::Xxh3] {
+        if let Some(decoder) = decoder(codec) {
+            let _ = decoder.decode_bytes(data, 0);
+        }
+    }
+}
+
+/// Map `data` as a data file and load it with every [RecoveryStrategy], it must not panic
+pub fn memory_map_iter(data: &[u8]) {
+    let path = fuzz_file_path();
+    if fs::write(&path, data).is_err() {
+        return;
+    }
+    let file = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let mm = match MemoryMap::new(&file, data.len(), false, Codec::legacy()) {
+        Ok(mm) => mm,
+        Err(_) => return,
+    };
+    let Some(decoder) = decoder(mm.codec()) else {
+        return;
+    };
+    for recovery in [
+        RecoveryStrategy::Stop,
+        RecoveryStrategy::Resync,
+        RecoveryStrategy::Fail,
+    ] {
+        let _ = mm
+            .iter(recovery, |bytes, position| {
+                decoder.decode_bytes(bytes, position)
+            })
+            .into_map();
+    }
+}
@@ -0,0 +1,245 @@
+use crate::Error::{CodecMismatch, UnrecognizedFormat, UnsupportedVersion};
+use crate::Result;
+use crate::core::buffer::{LEN_PREFIX, record_body};
+
+const MAGIC: [u8; 4] = *b"MMKV";
+/// The version of the file format written by this crate
+pub const FORMAT_VERSION: u16 = 1;
+/// Magic (4 bytes), version (2 bytes), codec (1 byte), flags (1 byte),
+/// followed by the 8 bytes big-endian sequence and the 8 bytes big-endian content len
+pub const HEADER_LEN: usize = 24;
+/// Magic, version, codec and flags, written by [Header::to_bytes]
+const PREFIX_LEN: usize = 8;
+/// The files written before the header was introduced only have the content len
+pub const LEGACY_HEADER_LEN: usize = 8;
+
+/// How the records in the file are encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Crc8 = 0,
+    AesEax = 1,
+    Crc32c = 2,
+    Xxh3 = 3,
+}
+
+impl Codec {
+    /// The codec of the files written before the header was introduced
+    #[cfg(any(test, fuzzing))]
+    pub fn legacy() -> Self {
+        if cfg!(feature = "encryption") {
+            Codec::AesEax
+        } else {
+            Codec::Crc8
+        }
+    }
+
+    pub fn is_encrypted(self) -> bool {
+        self == Codec::AesEax
+    }
+
+    /// The codec of the legacy files read as the files encoded with this codec
+    pub fn legacy_of(self) -> Self {
+        if self.is_encrypted() {
+            Codec::AesEax
+        } else {
+            Codec::Crc8
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Codec::Crc8),
+            1 => Some(Codec::AesEax),
+            2 => Some(Codec::Crc32c),
+            3 => Some(Codec::Xxh3),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub codec: Codec,
+    pub flags: u8,
+}
+
+/// The layout of a mapped file, detected by [Header::detect]
+#[derive(Debug, PartialEq)]
+pub enum Layout {
+    /// A new file, the header should be written
+    Empty,
+    /// A file written before the header was introduced, should be upgraded
+    Legacy,
+    Current(Header),
+}
+
+impl Header {
+    pub fn new(codec: Codec) -> Self {
+        Header {
+            version: FORMAT_VERSION,
+            codec,
+            flags: 0,
+        }
+    }
+
+    /// The header bytes before the sequence
+    pub fn to_bytes(self) -> [u8; PREFIX_LEN] {
+        let mut bytes = [0u8; PREFIX_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_be_bytes());
+        bytes[6] = self.codec as u8;
+        bytes[7] = self.flags;
+        bytes
+    }
+
+    /// Detect the layout of the file from all its `bytes`, reject files which are not written
+    /// by MMKV, or are encrypted differently from the `expected` codec
+    pub fn detect(bytes: &[u8], expected: Codec) -> Result<Layout> {
+        if bytes.len() < HEADER_LEN {
+            return Err(UnrecognizedFormat(format!(
+                "file len {} is smaller than the header",
+                bytes.len()
+            )));
+        }
+        if bytes[0..4] == MAGIC {
+            let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+            if version > FORMAT_VERSION {
+                return Err(UnsupportedVersion(version));
+            }
+            let codec = Codec::from_u8(bytes[6])
+                .ok_or_else(|| UnrecognizedFormat(format!("unknown codec {}", bytes[6])))?;
+            let header = Header {
+                version,
+                codec,
+                flags: bytes[7],
+            };
+            if codec.is_encrypted() != expected.is_encrypted() {
+                let expected = if expected.is_encrypted() {
+                    "an encrypted"
+                } else {
+                    "a plaintext"
+                };
+                return Err(CodecMismatch(format!(
+                    "file is encoded with {:?}, but {} file is expected",
+                    codec, expected
+                )));
+            }
+            return Ok(Layout::Current(header));
+        }
+        if bytes.iter().all(|&byte| byte == 0) {
+            return Ok(Layout::Empty);
+        }
+        let legacy_len = u64::from_be_bytes(bytes[0..LEGACY_HEADER_LEN].try_into().unwrap());
+        // A legacy file trimmed to no records only had its len zeroed, the old bytes remain
+        let content = usize::try_from(legacy_len)
+            .ok()
+            .and_then(|len| bytes[LEGACY_HEADER_LEN..].get(..len));
+        match content {
+            Some(content) if Header::is_legacy_content(content) => Ok(Layout::Legacy),
+            _ => Err(UnrecognizedFormat(format!(
+                "no magic, and the content len {} doesn't match the records of the file",
+                legacy_len
+            ))),
+        }
+    }
+
+    /// Whether the length prefixes of the records in `content` exactly cover it
+    fn is_legacy_content(mut content: &[u8]) -> bool {
+        while !content.is_empty() {
+            let Ok(body) = record_body(content, 1) else {
+                return false;
+            };
+            content = &content[LEN_PREFIX + body.len()..];
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Error::{CodecMismatch, UnrecognizedFormat, UnsupportedVersion};
+
+    use super::{Codec, FORMAT_VERSION, HEADER_LEN, Header, Layout};
+
+    /// The header followed by zeros up to 64 bytes
+    fn header_bytes(header: Header, content_len: u64) -> Vec<u8> {
+        let mut bytes = header.to_bytes().to_vec();
+        bytes.extend(0u64.to_be_bytes());
+        bytes.extend(content_len.to_be_bytes());
+        bytes.resize(64, 0);
+        bytes
+    }
+
+    /// A legacy file with records of `record_lens`, followed by zeros up to 64 bytes
+    fn legacy_bytes(record_lens: &[u32]) -> Vec<u8> {
+        let content_len: u32 = record_lens.iter().map(|len| len + 4).sum();
+        let mut bytes = (content_len as u64).to_be_bytes().to_vec();
+        for len in record_lens {
+            bytes.extend(len.to_be_bytes());
+            bytes.extend(vec![1; *len as usize]);
+        }
+        bytes.resize(64, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_detect_layout() {
+        let detect = |bytes: &[u8]| Header::detect(bytes, Codec::legacy());
+        let header = Header::new(Codec::legacy());
+        let bytes = header_bytes(header, 0);
+        assert_eq!(bytes[HEADER_LEN..], [0; 64 - HEADER_LEN]);
+        assert_eq!(detect(&bytes), Ok(Layout::Current(header)));
+        assert_eq!(detect(&[0; 64]), Ok(Layout::Empty));
+        assert!(matches!(detect(&[0; 8]), Err(UnrecognizedFormat(_))));
+
+        // A headerless file is only empty if it's entirely zero, the old records are kept
+        // after the zero len of a trimmed legacy file
+        let mut trimmed = legacy_bytes(&[16, 4]);
+        trimmed[..8].fill(0);
+        assert_eq!(detect(&trimmed), Ok(Layout::Legacy));
+
+        let legacy = legacy_bytes(&[16, 4]);
+        assert_eq!(detect(&legacy), Ok(Layout::Legacy));
+        assert!(matches!(detect(&legacy[..24]), Err(UnrecognizedFormat(_))));
+        // The content len must be exactly covered by the records
+        let mut misaligned = legacy.clone();
+        misaligned[7] -= 1;
+        assert!(matches!(detect(&misaligned), Err(UnrecognizedFormat(_))));
+        let mut oversized = legacy.clone();
+        oversized[11] += 1;
+        assert!(matches!(detect(&oversized), Err(UnrecognizedFormat(_))));
+        assert!(matches!(detect(&[1; 64]), Err(UnrecognizedFormat(_))));
+
+        let newer = Header {
+            version: FORMAT_VERSION + 1,
+            ..header
+        };
+        assert_eq!(
+            detect(&header_bytes(newer, 0)),
+            Err(UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+
+        let codec = match Codec::legacy() {
+            Codec::AesEax => Codec::Crc32c,
+            _ => Codec::AesEax,
+        };
+        // Even without records, the file isn't reused with another codec
+        for content_len in [8, 0] {
+            assert!(matches!(
+                detect(&header_bytes(Header::new(codec), content_len)),
+                Err(CodecMismatch(_))
+            ));
+        }
+        for codec in [Codec::Crc8, Codec::Crc32c, Codec::Xxh3, Codec::AesEax] {
+            let header = Header::new(codec);
+            assert_eq!(
+                Header::detect(&header_bytes(header, 8), codec.legacy_of()),
+                Ok(Layout::Current(header))
+            );
+        }
+        let mut unknown = header_bytes(header, 0);
+        unknown[6] = 9;
+        assert!(matches!(detect(&unknown), Err(UnrecognizedFormat(_))));
+    }
+}
@@ -1,8 +1,8 @@
 use crate::Error::IOError;
 use crate::Result;
-use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_channel::{Receiver, Sender, bounded, unbounded};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Instant;
@@ -79,6 +79,15 @@ impl<T: Executor> IOLooper<T> {
 
     /// Execute a task and wait for the task result.
     pub fn call<R, F>(&self, task: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut T) -> Result<R> + Send + 'static,
+    {
+        self.submit(task)?.wait()
+    }
+
+    /// Post a task async, the task result can be received from the returned [Completion].
+    pub fn submit<R, F>(&self, task: F) -> Result<Completion<R>>
     where
         R: Send + 'static,
         F: FnOnce(&mut T) -> Result<R> + Send + 'static,
@@ -90,9 +99,7 @@ impl<T: Executor> IOLooper<T> {
                 .send(result)
                 .map_err(|e| IOError(format!("failed to return call result: {e}")))
         })?;
-        receiver
-            .recv()
-            .map_err(|_| IOError("failed to receive call result, channel closed".to_string()))?
+        Ok(Completion(receiver))
     }
 
     #[allow(dead_code)]
@@ -101,6 +108,18 @@ impl<T: Executor> IOLooper<T> {
     }
 }
 
+/// The pending result of a task posted by [IOLooper::submit]
+pub struct Completion<R>(Receiver<Result<R>>);
+
+impl<R> Completion<R> {
+    /// Wait for the task to finish and return the task result.
+    pub fn wait(self) -> Result<R> {
+        self.0
+            .recv()
+            .map_err(|_| IOError("failed to receive call result, channel closed".to_string()))?
+    }
+}
+
 impl<T> Drop for IOLooper<T> {
     fn drop(&mut self) {
         let time_start = Instant::now();
@@ -349,4 +368,28 @@ mod tests {
             .unwrap();
         assert_eq!(value, 5);
     }
+
+    #[test]
+    fn test_submit_returns_result_after_previous_jobs() {
+        struct CountingExecutor {
+            value: usize,
+        }
+
+        impl Executor for CountingExecutor {}
+
+        let io_looper = IOLooper::new(CountingExecutor { value: 0 });
+        io_looper
+            .post(|executor| {
+                thread::sleep(Duration::from_millis(50));
+                executor.value += 1;
+                Ok(())
+            })
+            .unwrap();
+        let completion = io_looper.submit(|executor| Ok(executor.value)).unwrap();
+        let failure = io_looper
+            .submit(|_| Err::<(), _>(crate::Error::DataInvalid))
+            .unwrap();
+        assert_eq!(completion.wait(), Ok(1));
+        assert_eq!(failure.wait(), Err(crate::Error::DataInvalid));
+    }
 }
@@ -1,32 +1,66 @@
-use crate::core::buffer::{Buffer, DecodeResult};
+use crate::Error::DataInvalid;
+use crate::Result;
+use crate::core::buffer::{Buffer, DecodeResult, LEN_PREFIX, record_body};
 use crate::core::memory_map::MemoryMap;
+use crate::core::recovery::{RecoveryReport, RecoveryStrategy};
 use std::collections::HashMap;
 
 const LOG_TAG: &str = "MMKV:MemoryMap";
 
 pub struct Iter<'a, F>
 where
-    F: Fn(&[u8], u32) -> crate::Result<DecodeResult>,
+    F: Fn(&[u8], u32) -> Result<DecodeResult>,
 {
     mm: &'a MemoryMap,
     pub position: u32,
     start: usize,
     end: usize,
+    /// The len of the record last returned by `next`
+    record_len: usize,
+    recovery: RecoveryStrategy,
+    report: RecoveryReport,
     decode: F,
 }
 
 impl MemoryMap {
-    pub fn iter<F>(&self, decode: F) -> Iter<'_, F>
+    pub fn iter<F>(&self, recovery: RecoveryStrategy, decode: F) -> Iter<'_, F>
     where
-        F: Fn(&[u8], u32) -> crate::Result<DecodeResult>,
+        F: Fn(&[u8], u32) -> Result<DecodeResult>,
     {
-        let start = self.content_start_offset();
+        self.iter_from(self.content_start_offset(), 0, recovery, decode)
+    }
+
+    /// Iterate the records from `start`, where the record at `position` begins
+    pub fn iter_from<F>(
+        &self,
+        start: usize,
+        position: u32,
+        recovery: RecoveryStrategy,
+        decode: F,
+    ) -> Iter<'_, F>
+    where
+        F: Fn(&[u8], u32) -> Result<DecodeResult>,
+    {
+        let mut report = RecoveryReport::default();
         let end = self.write_offset();
+        if end > self.len() {
+            // The content len is corrupted, read what's mapped
+            error!(
+                LOG_TAG,
+                "content end {} exceeds the mmap len {}",
+                end,
+                self.len()
+            );
+            report.lose(end - self.len());
+        }
         Iter {
             mm: self,
-            position: 0,
+            position,
             start,
-            end,
+            end: end.min(self.len()),
+            record_len: 0,
+            recovery,
+            report,
             decode,
         }
     }
@@ -34,28 +68,89 @@ impl MemoryMap {
 
 impl<F> Iter<'_, F>
 where
-    F: Fn(&[u8], u32) -> crate::Result<DecodeResult>,
+    F: Fn(&[u8], u32) -> Result<DecodeResult>,
 {
-    pub fn into_map(self) -> (HashMap<String, Buffer>, u32) {
-        let mut iter_count = 0;
+    /// Collect the items and the position of the next record,
+    /// fail with [DataInvalid] if any record is lost with [RecoveryStrategy::Fail]
+    pub fn into_map(self) -> Result<(HashMap<String, Buffer>, u32, RecoveryReport)> {
         let mut map = HashMap::new();
-        self.for_each(|buffer| {
-            iter_count += 1;
-            if let Some(data) = buffer {
-                if data.is_deleting() {
-                    map.remove(data.key());
-                } else {
-                    map.insert(data.key().to_string(), data);
-                }
+        let (position, report) = self.apply_to(&mut map)?;
+        Ok((map, position, report))
+    }
+
+    /// Same as [Iter::into_map], but apply the items to an existing `map`
+    pub fn apply_to(mut self, map: &mut HashMap<String, Buffer>) -> Result<(u32, RecoveryReport)> {
+        while let Some(buffer) = self.next() {
+            if let Some(data) = buffer
+                && let Err(e) = apply(map, data)
+            {
+                error!(LOG_TAG, "Failed to unpack batch, reason: {:?}", e);
+                self.report.lose(self.record_len);
+            }
+        }
+        if !self.report.is_clean() {
+            error!(LOG_TAG, "lost records while loading: {:?}", self.report);
+            if self.recovery == RecoveryStrategy::Fail {
+                return Err(DataInvalid);
             }
-        });
-        (map, iter_count)
+        }
+        Ok((self.position, self.report))
+    }
+
+    // Decode the record at the start of `bytes`, None if its length is unreadable
+    fn decode_at(&self, bytes: &[u8], position: u32) -> Option<DecodeResult> {
+        let record_len = LEN_PREFIX + record_body(bytes, 1).ok()?.len();
+        (self.decode)(&bytes[..record_len], position)
+            .ok()
+            .filter(|result| result.len as usize == record_len)
+    }
+
+    // Scan for the next record which decodes after an unreadable one
+    fn resync(&mut self, bytes: &[u8]) -> Option<Option<Buffer>> {
+        for offset in 1..bytes.len() {
+            let result = match self.decode_at(&bytes[offset..], self.position) {
+                Some(result) if result.buffer.is_some() => result,
+                _ => continue,
+            };
+            warn!(
+                LOG_TAG,
+                "resynced at offset {}, skipped {} bytes",
+                self.start + offset,
+                offset
+            );
+            self.report.lose(offset);
+            self.position += 1;
+            self.start += offset + result.len as usize;
+            self.record_len = result.len as usize;
+            return Some(result.buffer);
+        }
+        None
+    }
+}
+
+/// Apply a record to the `map`, the items of a batch are applied in order
+pub fn apply(map: &mut HashMap<String, Buffer>, data: Buffer) -> Result<()> {
+    if data.is_batch() {
+        data.batch_items()?
+            .into_iter()
+            .for_each(|item| apply_item(map, item));
+    } else {
+        apply_item(map, data);
+    }
+    Ok(())
+}
+
+fn apply_item(map: &mut HashMap<String, Buffer>, data: Buffer) {
+    if data.is_deleting() {
+        map.remove(data.key());
+    } else {
+        map.insert(data.key().to_string(), data);
     }
 }
 
 impl<F> Iterator for Iter<'_, F>
 where
-    F: Fn(&[u8], u32) -> crate::Result<DecodeResult>,
+    F: Fn(&[u8], u32) -> Result<DecodeResult>,
 {
     type Item = Option<Buffer>;
 
@@ -70,18 +165,28 @@ where
                 return None;
             }
         };
-        let decode_result = (self.decode)(bytes, self.position);
+        let decode_result = self.decode_at(bytes, self.position);
         self.position += 1;
-        match decode_result {
-            Ok(result) => {
-                self.start += result.len as usize;
-                Some(result.buffer)
-            }
-            Err(e) => {
-                error!(LOG_TAG, "Failed to iter memory map, reason: {:?}", e);
-                None
+        if let Some(result) = decode_result {
+            self.start += result.len as usize;
+            self.record_len = result.len as usize;
+            if result.buffer.is_none() {
+                self.report.lose(result.len as usize);
             }
+            return Some(result.buffer);
+        }
+        error!(
+            LOG_TAG,
+            "Failed to iter memory map, unreadable record at offset {}", self.start
+        );
+        if self.recovery == RecoveryStrategy::Resync
+            && let Some(buffer) = self.resync(bytes)
+        {
+            return Some(buffer);
         }
+        self.report.lose(bytes.len());
+        self.start = self.end;
+        None
     }
 }
 
@@ -90,11 +195,19 @@ mod tests {
     use std::fs;
     use std::fs::OpenOptions;
     use std::mem::size_of;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use protobuf::Message;
 
     use crate::Error::DataInvalid;
     use crate::Result;
+    use crate::core::buffer::kv::KV;
     use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder};
+    use crate::core::config::Checksum;
+    use crate::core::header::{Codec, HEADER_LEN};
     use crate::core::memory_map::MemoryMap;
+    use crate::core::recovery::RecoveryStrategy;
 
     const LOG_TAG: &str = "MMKV:IterTest";
 
@@ -107,6 +220,22 @@ mod tests {
             data.extend_from_slice(bytes_to_write.as_slice());
             Ok(data)
         }
+
+        fn codec(&self) -> Codec {
+            Codec::legacy()
+        }
+
+        fn for_new_file(&self, _: &Path, _: Checksum) -> Result<Box<dyn Encoder>> {
+            Ok(Box::new(TestEncoderDecoder))
+        }
+
+        fn for_existing_file(&self, _: &Path, _: Codec) -> Result<Box<dyn Encoder>> {
+            Ok(Box::new(TestEncoderDecoder))
+        }
+
+        fn decoder(&self) -> Box<dyn Decoder> {
+            Box::new(TestEncoderDecoder)
+        }
     }
 
     impl Decoder for TestEncoderDecoder {
@@ -142,7 +271,7 @@ mod tests {
             .open(file_name)
             .unwrap();
         file.set_len(1024).unwrap();
-        let mut mm = MemoryMap::new(&file, 1024).unwrap();
+        let mut mm = MemoryMap::new(&file, 1024, true, Codec::legacy()).unwrap();
         let mut buffers: Vec<Buffer> = vec![];
         let test_encoder = &TestEncoderDecoder;
         for i in 0..10 {
@@ -153,11 +282,82 @@ mod tests {
         }
         let decoder = &TestEncoderDecoder;
         for (index, i) in mm
-            .iter(|bytes, position| decoder.decode_bytes(bytes, position))
+            .iter(RecoveryStrategy::Stop, |bytes, position| {
+                decoder.decode_bytes(bytes, position)
+            })
             .enumerate()
         {
             assert_eq!(buffers[index], i.unwrap());
         }
         let _ = fs::remove_file("test_mmap_iterator");
     }
+
+    #[test]
+    fn test_iter_corrupted_content_len() {
+        let file_name = "test_iter_corrupted_content_len";
+        let _ = fs::remove_file(file_name);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(file_name)
+            .unwrap();
+        file.set_len(1024).unwrap();
+        let mut mm = MemoryMap::new(&file, 1024, true, Codec::legacy()).unwrap();
+        for i in 0..10 {
+            let buffer = Buffer::new(&i.to_string(), i);
+            mm.append(&TestEncoderDecoder.encode_to_bytes(&buffer, 0).unwrap())
+                .unwrap();
+        }
+        drop(mm);
+        // The content len right before the records
+        file.write_at(&u64::MAX.to_be_bytes(), (HEADER_LEN - 8) as u64)
+            .unwrap();
+        let mm = MemoryMap::new(&file, 1024, true, Codec::legacy()).unwrap();
+        let decode = |bytes: &[u8], position| TestEncoderDecoder.decode_bytes(bytes, position);
+        let (map, _, report) = mm.iter(RecoveryStrategy::Stop, decode).into_map().unwrap();
+        assert_eq!(map.len(), 10);
+        // The part beyond the file, and the zeros after the records
+        assert_eq!(report.records_lost, 2);
+        assert_eq!(
+            mm.iter(RecoveryStrategy::Fail, decode).into_map().err(),
+            Some(DataInvalid)
+        );
+        let _ = fs::remove_file(file_name);
+    }
+
+    #[test]
+    fn test_iter_corrupted_batch() {
+        let file_name = "test_iter_corrupted_batch";
+        let _ = fs::remove_file(file_name);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(file_name)
+            .unwrap();
+        file.set_len(1024).unwrap();
+        let mut mm = MemoryMap::new(&file, 1024, true, Codec::legacy()).unwrap();
+        // A batch whose items can't be unpacked
+        let mut kv = KV::parse_from_bytes(&Buffer::batch_buffer(&[]).to_bytes()).unwrap();
+        kv.value = vec![0xFF];
+        let batch = Buffer::from_encoded_bytes(&kv.write_to_bytes().unwrap()).unwrap();
+        let batch_len = TestEncoderDecoder.encode_to_bytes(&batch, 0).unwrap().len();
+        for buffer in [Buffer::new("key1", 1), batch, Buffer::new("key2", 2)] {
+            mm.append(&TestEncoderDecoder.encode_to_bytes(&buffer, 0).unwrap())
+                .unwrap();
+        }
+        let decode = |bytes: &[u8], position| TestEncoderDecoder.decode_bytes(bytes, position);
+        let (map, _, report) = mm.iter(RecoveryStrategy::Stop, decode).into_map().unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(report.records_lost, 1);
+        assert_eq!(report.bytes_lost, batch_len);
+        assert_eq!(
+            mm.iter(RecoveryStrategy::Fail, decode).into_map().err(),
+            Some(DataInvalid)
+        );
+        let _ = fs::remove_file(file_name);
+    }
 }
@@ -1,5 +1,6 @@
-use crate::Error::IOError;
+use crate::Error::{IOError, ReadOnly};
 use crate::Result;
+use crate::core::header::{Codec, HEADER_LEN, Header, LEGACY_HEADER_LEN, Layout};
 use std::fs::File;
 use std::ops::{Deref, DerefMut, Range};
 use std::os::fd::{AsRawFd, RawFd};
@@ -7,7 +8,6 @@ use std::ptr::NonNull;
 use std::{io, ptr, slice};
 
 const LOG_TAG: &str = "MMKV:MemoryMap";
-const LEN_OFFSET: usize = 8;
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 const MAP_POPULATE: libc::c_int = libc::MAP_POPULATE;
@@ -22,20 +22,25 @@ struct RawMmap {
 }
 
 impl RawMmap {
-    fn new(fd: RawFd, len: usize) -> io::Result<RawMmap> {
+    fn new(fd: RawFd, len: usize, populate: bool, writable: bool) -> io::Result<RawMmap> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let flags = if populate {
+            libc::MAP_SHARED | MAP_POPULATE
+        } else {
+            libc::MAP_SHARED
+        };
         unsafe {
-            let ptr = libc::mmap(
-                ptr::null_mut(),
-                len as libc::size_t,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED | MAP_POPULATE,
-                fd,
-                0,
-            );
+            let ptr = libc::mmap(ptr::null_mut(), len as libc::size_t, prot, flags, fd, 0);
             if ptr == libc::MAP_FAILED {
                 Err(io::Error::last_os_error())
             } else {
-                libc::madvise(ptr, len, libc::MADV_WILLNEED);
+                if populate {
+                    libc::madvise(ptr, len, libc::MADV_WILLNEED);
+                }
                 Ok(RawMmap {
                     ptr: NonNull::new(ptr)
                         .ok_or_else(|| io::Error::other("mmap returned null pointer"))?,
@@ -82,9 +87,16 @@ unsafe impl Send for RawMmap {}
 
 unsafe impl Sync for RawMmap {}
 
+/// The mapped file, starts with a [Header], the sequence and the content len,
+/// followed by the records
 #[derive(Debug)]
-#[repr(transparent)]
-pub struct MemoryMap(RawMmap);
+pub struct MemoryMap {
+    raw: RawMmap,
+    // The end of header, where the records start, the content len is right before it
+    content_offset: usize,
+    codec: Codec,
+    writable: bool,
+}
 
 impl Drop for MemoryMap {
     fn drop(&mut self) {
@@ -98,23 +110,99 @@ impl Drop for MemoryMap {
             );
             return;
         }
-        if let Err(e) = self.0.flush(flush_len) {
+        if let Err(e) = self.raw.flush(flush_len) {
             error!(LOG_TAG, "failed to flush mmap on drop: {e}");
         }
     }
 }
 
 impl MemoryMap {
-    pub fn new(file: &File, len: usize) -> Result<Self> {
-        let raw_mmap = RawMmap::new(file.as_raw_fd(), len)
+    /**
+    Map the file with `len`, `populate` controls whether to prefault the pages.
+
+    The header with `codec` is written if the file is empty, otherwise the codec in the header
+    is used, a file in the legacy layout or an older version is mapped as is,
+    it should be upgraded by rewriting, see [needs_upgrade](MemoryMap::needs_upgrade).
+    */
+    pub fn new(file: &File, len: usize, populate: bool, codec: Codec) -> Result<Self> {
+        MemoryMap::map(file, len, populate, codec, true)
+    }
+
+    /// Map the file with `PROT_READ` like [new](MemoryMap::new), the file is never written,
+    /// an empty file is mapped without header and [append](MemoryMap::append) fails
+    pub fn read_only(file: &File, len: usize, populate: bool, codec: Codec) -> Result<Self> {
+        MemoryMap::map(file, len, populate, codec, false)
+    }
+
+    fn map(file: &File, len: usize, populate: bool, codec: Codec, writable: bool) -> Result<Self> {
+        let raw = RawMmap::new(file.as_raw_fd(), len, populate, writable)
             .map_err(|e| IOError(format!("failed to create mmap with len {len}: {e}")))?;
-        Ok(MemoryMap(raw_mmap))
+        let layout = Header::detect(&raw[..], codec)?;
+        let mut mm = MemoryMap {
+            raw,
+            content_offset: HEADER_LEN,
+            codec,
+            writable,
+        };
+        match layout {
+            Layout::Empty if !writable => {
+                verbose!(LOG_TAG, "found empty file in read-only mode");
+            }
+            Layout::Empty => {
+                let header = Header::new(codec);
+                let prefix = header.to_bytes();
+                mm.raw[0..prefix.len()].copy_from_slice(&prefix);
+                mm.set_sequence(0);
+                mm.reset();
+            }
+            Layout::Legacy => {
+                info!(LOG_TAG, "found file in legacy layout");
+                mm.content_offset = LEGACY_HEADER_LEN;
+                mm.codec = codec.legacy_of();
+            }
+            Layout::Current(header) => {
+                verbose!(LOG_TAG, "found file with {:?}", header);
+                mm.codec = header.codec;
+            }
+        }
+        Ok(mm)
+    }
+
+    /// The codec of the records in the file
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Whether the file is written before the header was introduced
+    pub fn needs_upgrade(&self) -> bool {
+        self.content_offset != HEADER_LEN
+    }
+
+    /// The sequence bumped by every change of the file, 0 if the file has no sequence
+    pub fn sequence(&self) -> u64 {
+        if self.needs_upgrade() {
+            return 0;
+        }
+        let range = HEADER_LEN - 2 * LEGACY_HEADER_LEN..HEADER_LEN - LEGACY_HEADER_LEN;
+        u64::from_be_bytes(self.raw[range].try_into().unwrap())
+    }
+
+    /// Set the sequence, ignored if the file has no sequence
+    pub fn set_sequence(&mut self, sequence: u64) {
+        if self.needs_upgrade() || !self.writable {
+            return;
+        }
+        let range = HEADER_LEN - 2 * LEGACY_HEADER_LEN..HEADER_LEN - LEGACY_HEADER_LEN;
+        self.raw[range].copy_from_slice(&sequence.to_be_bytes());
     }
 
     pub fn append(&mut self, value: &[u8]) -> Result<()> {
+        if !self.writable {
+            return Err(ReadOnly);
+        }
         let data_len = value.len();
         let start = self.write_offset();
-        let content_len = start - LEN_OFFSET;
+        let content_len = start - self.content_offset;
         let end = start
             .checked_add(data_len)
             .ok_or_else(|| IOError("append overflowed target offset".to_string()))?;
@@ -130,28 +218,53 @@ impl MemoryMap {
         let new_content_len = content_len
             .checked_add(data_len)
             .ok_or_else(|| IOError("append overflowed content length".to_string()))?;
-        self.0[0..LEN_OFFSET].copy_from_slice(new_content_len.to_be_bytes().as_slice());
-        self.0[start..end].copy_from_slice(value);
+        self.write_content_len(new_content_len);
+        self.raw[start..end].copy_from_slice(value);
         Ok(())
     }
 
+    /// Flush the written content to file synchronously
+    pub fn flush(&self) -> Result<()> {
+        self.raw
+            .flush(self.write_offset().min(self.len()))
+            .map_err(|e| IOError(format!("failed to flush mmap: {e}")))
+    }
+
     pub fn reset(&mut self) {
-        let len = 0usize;
-        self.0[0..LEN_OFFSET].copy_from_slice(len.to_be_bytes().as_slice());
+        self.write_content_len(0);
+    }
+
+    fn write_content_len(&mut self, len: usize) {
+        if !self.writable {
+            return;
+        }
+        let len_offset = self.content_offset - LEGACY_HEADER_LEN;
+        self.raw[len_offset..self.content_offset].copy_from_slice(&(len as u64).to_be_bytes());
     }
 
     pub fn content_start_offset(&self) -> usize {
-        LEN_OFFSET
+        self.content_offset
     }
 
     /// The write offset of current mmap
     pub fn write_offset(&self) -> usize {
-        usize::from_be_bytes(self.0[0..LEN_OFFSET].try_into().unwrap()) + LEN_OFFSET
+        let len_offset = self.content_offset - LEGACY_HEADER_LEN;
+        let len_bytes = self.raw[len_offset..self.content_offset]
+            .try_into()
+            .unwrap();
+        // Saturate a corrupted len, it's checked against the mmap len by the readers
+        (u64::from_be_bytes(len_bytes) as usize).saturating_add(self.content_offset)
+    }
+
+    /// The encoded records between the content start and the write offset
+    #[cfg(feature = "encryption")]
+    pub fn records(&self) -> Result<&[u8]> {
+        self.read(self.content_offset..self.write_offset())
     }
 
     /// The max len of current mmap
     pub fn len(&self) -> usize {
-        self.0.len
+        self.raw.len
     }
 
     pub fn read(&self, range: Range<usize>) -> Result<&[u8]> {
@@ -163,7 +276,7 @@ impl MemoryMap {
                 self.len()
             )));
         }
-        Ok(self.0[range].as_ref())
+        Ok(self.raw[range].as_ref())
     }
 }
 
@@ -174,7 +287,8 @@ mod tests {
 
     use crate::Error::IOError;
 
-    use super::{LEN_OFFSET, MemoryMap};
+    use super::MemoryMap;
+    use crate::core::header::{Codec, HEADER_LEN, LEGACY_HEADER_LEN};
 
     #[test]
     fn test_mmap() {
@@ -187,13 +301,13 @@ mod tests {
             .open("test_mmap")
             .unwrap();
         file.set_len(1024).unwrap();
-        let mut mm = MemoryMap::new(&file, 1024).unwrap();
-        assert_eq!(mm.write_offset(), LEN_OFFSET);
+        let mut mm = MemoryMap::new(&file, 1024, true, Codec::legacy()).unwrap();
+        assert_eq!(mm.write_offset(), HEADER_LEN);
         mm.append(&[1, 2, 3]).unwrap();
         mm.append(&[4]).unwrap();
-        assert_eq!(mm.write_offset(), 12);
+        assert_eq!(mm.write_offset(), HEADER_LEN + 4);
 
-        let read = mm.read(8..10).unwrap();
+        let read = mm.read(HEADER_LEN..HEADER_LEN + 2).unwrap();
         assert_eq!(read.len(), 2);
         assert_eq!(read[0], 1);
         assert_eq!(read[1], 2);
@@ -202,15 +316,46 @@ mod tests {
 
         mm.reset();
         mm.append(&[5, 4, 3, 2, 1]).unwrap();
-        assert_eq!(mm.write_offset(), 13);
-        let read = mm.read(8..9).unwrap();
+        assert_eq!(mm.write_offset(), HEADER_LEN + 5);
+        assert_eq!(mm.sequence(), 0);
+        mm.set_sequence(3);
+        assert_eq!(mm.sequence(), 3);
+        assert_eq!(mm.write_offset(), HEADER_LEN + 5);
+        let read = mm.read(HEADER_LEN..HEADER_LEN + 1).unwrap();
         assert_eq!(read[0], 5);
 
-        let read = mm.read(9..10).unwrap();
+        let read = mm.read(HEADER_LEN + 1..HEADER_LEN + 2).unwrap();
         assert_eq!(read[0], 4);
         let _ = fs::remove_file("test_mmap");
     }
 
+    #[test]
+    fn test_mmap_legacy_layout() {
+        let _ = fs::remove_file("test_mmap_legacy_layout");
+        // A record of 2 bytes with its length prefix
+        let mut content = 6u64.to_be_bytes().to_vec();
+        content.extend([0, 0, 0, 2, 1, 2]);
+        content.resize(1024, 0);
+        fs::write("test_mmap_legacy_layout", &content).unwrap();
+        let file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open("test_mmap_legacy_layout")
+            .unwrap();
+        let mut mm = MemoryMap::new(&file, 1024, true, Codec::legacy()).unwrap();
+        assert!(mm.needs_upgrade());
+        assert_eq!(mm.sequence(), 0);
+        assert_eq!(mm.content_start_offset(), LEGACY_HEADER_LEN);
+        assert_eq!(mm.write_offset(), LEGACY_HEADER_LEN + 6);
+        mm.append(&[4]).unwrap();
+        assert_eq!(mm.read(0..LEGACY_HEADER_LEN).unwrap(), 7u64.to_be_bytes());
+        assert_eq!(
+            mm.read(LEGACY_HEADER_LEN..LEGACY_HEADER_LEN + 7).unwrap(),
+            [0, 0, 0, 2, 1, 2, 4]
+        );
+        let _ = fs::remove_file("test_mmap_legacy_layout");
+    }
+
     #[test]
     fn test_mmap_append_out_of_bounds() {
         let _ = fs::remove_file("test_mmap_append_out_of_bounds");
@@ -221,18 +366,18 @@ mod tests {
             .read(true)
             .open("test_mmap_append_out_of_bounds")
             .unwrap();
-        file.set_len((LEN_OFFSET + 1) as u64).unwrap();
-        let mut mm = MemoryMap::new(&file, LEN_OFFSET + 1).unwrap();
+        file.set_len((HEADER_LEN + 1) as u64).unwrap();
+        let mut mm = MemoryMap::new(&file, HEADER_LEN + 1, true, Codec::legacy()).unwrap();
 
         let err = mm.append(&[1, 2]).unwrap_err();
         assert_eq!(
             err,
             IOError(format!(
                 "append out of bounds, start {}, data len {}, end {}, mmap len {}",
-                LEN_OFFSET,
+                HEADER_LEN,
                 2,
-                LEN_OFFSET + 2,
-                LEN_OFFSET + 1
+                HEADER_LEN + 2,
+                HEADER_LEN + 1
             ))
         );
 
@@ -249,17 +394,17 @@ mod tests {
             .read(true)
             .open("test_mmap_read_out_of_bounds")
             .unwrap();
-        file.set_len((LEN_OFFSET + 1) as u64).unwrap();
-        let mm = MemoryMap::new(&file, LEN_OFFSET + 1).unwrap();
+        file.set_len((HEADER_LEN + 1) as u64).unwrap();
+        let mm = MemoryMap::new(&file, HEADER_LEN + 1, true, Codec::legacy()).unwrap();
 
-        let err = mm.read(LEN_OFFSET..LEN_OFFSET + 2).unwrap_err();
+        let err = mm.read(HEADER_LEN..HEADER_LEN + 2).unwrap_err();
         assert_eq!(
             err,
             IOError(format!(
                 "read out of bounds, range {}..{}, mmap len {}",
-                LEN_OFFSET,
-                LEN_OFFSET + 2,
-                LEN_OFFSET + 1
+                HEADER_LEN,
+                HEADER_LEN + 2,
+                HEADER_LEN + 1
             ))
         );
 
@@ -1,19 +1,27 @@
-use crate::Error::InstanceClosed;
-use crate::core::buffer::{Buffer, Decoder};
-use crate::core::config::Config;
-#[cfg(not(feature = "encryption"))]
-use crate::core::crc::CrcEncoderDecoder;
+use crate::Error::{InstanceClosed, ReadOnly};
+use crate::core::buffer::Buffer;
+#[cfg(feature = "encryption")]
+use crate::core::buffer::{Decoder, Encoder};
+use crate::core::checksum::codec_pair;
+#[cfg(feature = "encryption")]
+use crate::core::config::{Checksum, Key, meta_file_path};
+use crate::core::config::{Config, Options};
 #[cfg(feature = "encryption")]
 use crate::core::encrypt::Encryptor;
-use crate::core::io_looper::IOLooper;
-use crate::core::memory_map::MemoryMap;
+use crate::core::failure::{PendingChange, WriteFailures};
+#[cfg(feature = "encryption")]
+use crate::core::header::Codec;
+use crate::core::io_looper::{Completion, IOLooper};
+use crate::core::observer::{Change, Observers};
+use crate::core::recovery::RecoveryReport;
 use crate::core::shared_state::{SharedKvMap, new_shared_kv_map};
 use crate::core::writer::IOWriter;
 use crate::{Error, Result};
+use std::collections::HashMap;
 #[cfg(feature = "encryption")]
 use std::fs;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 const LOG_TAG: &str = "MMKV:Core";
 
@@ -21,42 +29,81 @@ pub struct MmkvImpl {
     is_valid: bool,
     io_looper: IOLooper<IOWriter>,
     shared_kv: SharedKvMap,
+    observers: Observers,
+    failures: WriteFailures,
+    default_ttl: Option<Duration>,
+    options: Options,
+    recovery_report: RecoveryReport,
     #[cfg(feature = "encryption")]
-    encryptor: Encryptor,
+    encryptor: Option<Encryptor>,
 }
 
 impl MmkvImpl {
-    pub fn new(config: Config, #[cfg(feature = "encryption")] key: &str) -> Result<Self> {
+    /// Load the file of `config`, which is encrypted if [Options::key] is set
+    pub fn new(mut config: Config) -> Result<Self> {
         let time_start = Instant::now();
+        // Other processes can't change the file until it's loaded and upgraded
+        let lock = if config.options().multi_process {
+            Some(config.lock(true)?.0)
+        } else {
+            None
+        };
+        let mm = IOWriter::map(&config, config.options().codec())?;
         #[cfg(feature = "encryption")]
-        let encryptor = Encryptor::init(&config.path, key);
+        let mut encryptor = match &config.options().key {
+            // A new nonce can't decrypt the records, and a read-only instance can't write it
+            Some(_)
+                if !meta_file_path(&config.path).exists()
+                    && (config.options().read_only
+                        || mm.write_offset() > mm.content_start_offset()) =>
+            {
+                return Err(Error::IOError(format!(
+                    "the meta file of encrypted file {} is missing",
+                    config.path.display()
+                )));
+            }
+            Some(key) => Some(Encryptor::init(&config.path, key)?),
+            None => None,
+        };
         #[cfg(feature = "encryption")]
-        let encoder = Box::new(encryptor.clone());
-        #[cfg(not(feature = "encryption"))]
-        let encoder = Box::new(CrcEncoderDecoder);
-        let mm = MemoryMap::new(&config.file, config.file_size()? as usize)?;
+        if let Some(encryptor) = &mut encryptor {
+            encryptor.check_legacy_key(mm.records()?, config.options().read_only)?;
+        }
         #[cfg(feature = "encryption")]
-        let decoder = Box::new(encryptor.clone());
+        let (encoder, decoder): (Box<dyn Encoder>, Box<dyn Decoder>) = match &encryptor {
+            Some(encryptor) => (Box::new(encryptor.clone()), Box::new(encryptor.clone())),
+            None => codec_pair(mm.codec())?,
+        };
         #[cfg(not(feature = "encryption"))]
-        let decoder = Box::new(CrcEncoderDecoder);
-        let (kv_map, decoded_position) = mm
-            .iter(|bytes, position| decoder.decode_bytes(bytes, position))
-            .into_map();
+        let (encoder, decoder) = codec_pair(mm.codec())?;
+        let (kv_map, decoded_position, recovery_report) = mm
+            .iter(config.options().recovery, |bytes, position| {
+                decoder.decode_bytes(bytes, position)
+            })
+            .into_map()?;
         let item_count = kv_map.len();
         let content_len = mm.write_offset();
         let file_size = mm.len();
         let shared_kv = new_shared_kv_map(kv_map);
-        let io_writer = IOWriter::new(
+        let options = config.options().clone();
+        let mut io_writer = IOWriter::new(
             config,
             mm,
             decoded_position,
             Arc::clone(&shared_kv),
             encoder,
         );
+        io_writer.repair(&recovery_report);
+        drop(lock);
         let mmkv = MmkvImpl {
             is_valid: true,
             io_looper: IOLooper::new(io_writer),
             shared_kv,
+            observers: Observers::default(),
+            failures: WriteFailures::default(),
+            default_ttl: None,
+            options,
+            recovery_report,
             #[cfg(feature = "encryption")]
             encryptor,
         };
@@ -71,11 +118,36 @@ impl MmkvImpl {
         Ok(mmkv)
     }
 
+    /// Open the file again, keep the observers and settings of this instance
+    pub fn reopen(&self, config: Config) -> Result<Self> {
+        let mut mmkv = MmkvImpl::new(config)?;
+        mmkv.observers = self.observers.clone();
+        mmkv.failures = self.failures.clone();
+        mmkv.default_ttl = self.default_ttl;
+        Ok(mmkv)
+    }
+
     pub fn put(&mut self, key: &str, raw_buffer: Buffer) -> Result<()> {
-        if !self.is_valid {
-            return Err(InstanceClosed);
-        }
+        self.put_buffer(key, raw_buffer, false).map(|_| ())
+    }
+
+    /// Same as [MmkvImpl::put], the returned [Completion] reports the write result
+    /// after the data flushed to file
+    pub fn put_sync(&mut self, key: &str, raw_buffer: Buffer) -> Result<Completion<()>> {
+        self.put_buffer(key, raw_buffer, true)
+            .map(|completion| completion.unwrap())
+    }
+
+    fn put_buffer(
+        &mut self,
+        key: &str,
+        raw_buffer: Buffer,
+        sync: bool,
+    ) -> Result<Option<Completion<()>>> {
+        self.check_writable()?;
+        self.sync_from_file()?;
         debug_assert_eq!(key, raw_buffer.key());
+        let raw_buffer = self.compress(self.apply_default_ttl(raw_buffer))?;
         let previous = {
             let mut kv_map = self
                 .shared_kv
@@ -84,33 +156,157 @@ impl MmkvImpl {
             kv_map.insert(key.to_string(), raw_buffer.clone())
         };
         let duplicated = previous.is_some();
-        if let Err(err) = self
-            .io_looper
-            .post(move |writer| writer.write(raw_buffer, duplicated))
-        {
+        let pending = PendingChange::new(key, raw_buffer.clone(), previous.clone());
+        // Notify before the write is posted, a rollback by the io thread is notified after it
+        if !self.observers.is_empty() {
+            self.observers.notify(vec![Change::new(
+                key,
+                previous.clone(),
+                Some(raw_buffer.clone()),
+            )]);
+        }
+        match self.post_write(raw_buffer.clone(), duplicated, vec![pending], sync) {
+            Ok(completion) => Ok(completion),
+            Err(err) => {
+                let mut kv_map = self
+                    .shared_kv
+                    .write()
+                    .map_err(|e| Error::LockError(e.to_string()))?;
+                if let Some(buffer) = &previous {
+                    kv_map.insert(key.to_string(), buffer.clone());
+                } else {
+                    kv_map.remove(key);
+                }
+                drop(kv_map);
+                if !self.observers.is_empty() {
+                    self.observers
+                        .notify(vec![Change::new(key, Some(raw_buffer), previous)]);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Apply all changes to the map at once, and write them as one record
+    pub fn batch(&mut self, changes: Vec<Buffer>) -> Result<()> {
+        self.check_writable()?;
+        self.sync_from_file()?;
+        // Nothing is applied if any value fails to compress
+        let changes = changes
+            .into_iter()
+            .map(|buffer| self.compress(self.apply_default_ttl(buffer)))
+            .collect::<Result<Vec<_>>>()?;
+        let (changes, previous) = {
             let mut kv_map = self
                 .shared_kv
                 .write()
                 .map_err(|e| Error::LockError(e.to_string()))?;
-            if let Some(buffer) = previous {
-                kv_map.insert(key.to_string(), buffer);
-            } else {
-                kv_map.remove(key);
+            let mut applied = Vec::with_capacity(changes.len());
+            let mut previous = Vec::with_capacity(changes.len());
+            for buffer in changes {
+                let key = buffer.key().to_string();
+                let replaced = if buffer.is_deleting() {
+                    kv_map.remove(&key)
+                } else {
+                    kv_map.insert(key.clone(), buffer.clone())
+                };
+                if buffer.is_deleting() && replaced.is_none() {
+                    continue;
+                }
+                applied.push(buffer);
+                previous.push((key, replaced));
+            }
+            (applied, previous)
+        };
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let duplicated = previous.iter().any(|(_, buffer)| buffer.is_some());
+        let batch_buffer = Buffer::batch_buffer(changes.as_slice());
+        let pending = changes
+            .iter()
+            .zip(previous.iter())
+            .map(|(buffer, (key, old))| PendingChange::new(key, buffer.clone(), old.clone()))
+            .collect();
+        // The values written by the batch, None for the deleted keys
+        let written: Vec<Option<Buffer>> = changes
+            .into_iter()
+            .map(|buffer| (!buffer.is_deleting()).then_some(buffer))
+            .collect();
+        // Notify before the write is posted, a rollback by the io thread is notified after it
+        if !self.observers.is_empty() {
+            let changes = written
+                .iter()
+                .zip(previous.iter())
+                .map(|(new, (key, old))| Change::new(key, old.clone(), new.clone()))
+                .collect();
+            self.observers.notify(changes);
+        }
+        if let Err(err) = self.post_write(batch_buffer, duplicated, pending, false) {
+            let mut kv_map = self
+                .shared_kv
+                .write()
+                .map_err(|e| Error::LockError(e.to_string()))?;
+            for (key, buffer) in &previous {
+                if let Some(buffer) = buffer {
+                    kv_map.insert(key.clone(), buffer.clone());
+                } else {
+                    kv_map.remove(key);
+                }
+            }
+            drop(kv_map);
+            if !self.observers.is_empty() {
+                let reverted = written
+                    .into_iter()
+                    .zip(previous)
+                    .map(|(written, (key, old))| Change::new(&key, written, old))
+                    .collect();
+                self.observers.notify(reverted);
             }
             return Err(err);
         }
         Ok(())
     }
 
+    /// Post the record to io thread, the failure of the write is recorded to [WriteFailures]
+    fn post_write(
+        &self,
+        record: Buffer,
+        duplicated: bool,
+        changes: Vec<PendingChange>,
+        sync: bool,
+    ) -> Result<Option<Completion<()>>> {
+        let failures = self.failures.clone();
+        let shared_kv = Arc::clone(&self.shared_kv);
+        let observers = self.observers.clone();
+        let task = move |writer: &mut IOWriter| {
+            let mut result = writer.write(record, duplicated);
+            if sync && result.is_ok() {
+                result = writer.flush();
+            }
+            if let Err(e) = &result {
+                failures.on_failure(e, &shared_kv, &observers, changes);
+            }
+            result
+        };
+        if sync {
+            self.io_looper.submit(task).map(Some)
+        } else {
+            self.io_looper.post(task).map(|_| None)
+        }
+    }
+
     pub fn get(&self, key: &str) -> Result<Buffer> {
         if !self.is_valid {
             return Err(InstanceClosed);
         }
+        self.sync_from_file()?;
         match self
             .shared_kv
             .read()
             .map_err(|e| Error::LockError(e.to_string()))?
             .get(key)
+            .filter(|buffer| !buffer.is_expired())
             .cloned()
         {
             Some(buffer) => Ok(buffer),
@@ -118,10 +314,66 @@ impl MmkvImpl {
         }
     }
 
-    pub fn delete(&mut self, key: &str) -> Result<()> {
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
         if !self.is_valid {
             return Err(InstanceClosed);
         }
+        self.sync_from_file()?;
+        Ok(self
+            .shared_kv
+            .read()
+            .map_err(|e| Error::LockError(e.to_string()))?
+            .get(key)
+            .is_some_and(|buffer| !buffer.is_expired()))
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        if !self.is_valid {
+            return Err(InstanceClosed);
+        }
+        self.sync_from_file()?;
+        Ok(self
+            .shared_kv
+            .read()
+            .map_err(|e| Error::LockError(e.to_string()))?
+            .iter()
+            .filter(|(_, buffer)| !buffer.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        if !self.is_valid {
+            return Err(InstanceClosed);
+        }
+        self.sync_from_file()?;
+        Ok(self
+            .shared_kv
+            .read()
+            .map_err(|e| Error::LockError(e.to_string()))?
+            .values()
+            .filter(|buffer| !buffer.is_expired())
+            .count())
+    }
+
+    pub fn snapshot(&self) -> Result<HashMap<String, Buffer>> {
+        if !self.is_valid {
+            return Err(InstanceClosed);
+        }
+        self.sync_from_file()?;
+        Ok(self
+            .shared_kv
+            .read()
+            .map_err(|e| Error::LockError(e.to_string()))?
+            .iter()
+            .filter(|(_, buffer)| !buffer.is_expired())
+            .map(|(key, buffer)| (key.clone(), buffer.clone()))
+            .collect())
+    }
+
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        self.check_writable()?;
+        self.sync_from_file()?;
         let key = key.to_string();
         let previous = {
             let mut kv_map = self
@@ -133,37 +385,228 @@ impl MmkvImpl {
         if previous.is_none() {
             return Ok(());
         }
-        if let Err(err) = self.io_looper.post({
-            let key = key.clone();
-            move |writer| writer.write(Buffer::deleted_buffer(&key), true)
-        }) {
+        let deleted_buffer = Buffer::deleted_buffer(&key);
+        let pending = PendingChange::new(&key, deleted_buffer.clone(), previous.clone());
+        // Notify before the write is posted, a rollback by the io thread is notified after it
+        if !self.observers.is_empty() {
+            self.observers
+                .notify(vec![Change::new(&key, previous.clone(), None)]);
+        }
+        if let Err(err) = self.post_write(deleted_buffer, true, vec![pending], false) {
             let mut kv_map = self
                 .shared_kv
                 .write()
                 .map_err(|e| Error::LockError(e.to_string()))?;
-            kv_map.insert(key, previous.unwrap());
+            kv_map.insert(key.clone(), previous.clone().unwrap());
+            drop(kv_map);
+            if !self.observers.is_empty() {
+                self.observers
+                    .notify(vec![Change::new(&key, None, previous)]);
+            }
             return Err(err);
         }
         Ok(())
     }
 
+    /// Wait for all pending writes to finish, then flush the data to file,
+    /// returns the last write failure if any
+    pub fn flush(&self) -> Result<()> {
+        if !self.is_valid {
+            return Err(InstanceClosed);
+        }
+        self.io_looper.call(|writer| writer.flush())?;
+        match self.failures.last_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Apply the changes written by other processes if the file is shared by processes,
+    // or reload the changed file, the io thread runs it after the pending writes of this process
+    fn sync_from_file(&self) -> Result<()> {
+        if !self.options.multi_process && !self.options.detect_changes {
+            return Ok(());
+        }
+        self.io_looper.call(|writer| writer.sync())
+    }
+
+    /// Load the file again after the pending writes finished, the map is rebuilt from it
+    pub fn reload(&mut self) -> Result<()> {
+        if !self.is_valid {
+            return Err(InstanceClosed);
+        }
+        self.recovery_report = self.io_looper.call(|writer| writer.reload())?;
+        Ok(())
+    }
+
+    /// Rewrite the file under the new `key` after the pending writes finished,
+    /// the file encrypted with the current key is replaced atomically
+    #[cfg(feature = "encryption")]
+    pub fn rekey(&mut self, key: Key) -> Result<()> {
+        self.check_writable()?;
+        if self.options.multi_process {
+            // Other processes would keep reading the new file with the old key
+            return Err(Error::InvalidConfig(
+                "rekey is not supported in multi-process mode".to_string(),
+            ));
+        }
+        let Some(current) = &self.encryptor else {
+            return Err(Error::InvalidConfig(
+                "the instance is not encrypted".to_string(),
+            ));
+        };
+        let encryptor = current.with_key(&key);
+        let template = Box::new(encryptor.clone());
+        self.io_looper
+            .call(move |writer| writer.rewrite_with(template))?;
+        self.encryptor = Some(encryptor);
+        // The file is opened with the new key if it's cleared
+        self.options.key = Some(key);
+        info!(LOG_TAG, "rekeyed");
+        Ok(())
+    }
+
+    /// Encrypt the plaintext file of `config` which isn't opened with `key`
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_file(config: Config, key: &Key) -> Result<()> {
+        let template = Encryptor::ephemeral(meta_file_path(&config.path), key);
+        IOWriter::convert(
+            config,
+            Checksum::default().codec(),
+            |mm| codec_pair(mm.codec()),
+            Box::new(template),
+        )
+    }
+
+    /// Decrypt the file of `config` which isn't opened with `key`,
+    /// the records are protected by the default [Checksum] afterwards
+    #[cfg(feature = "encryption")]
+    pub fn decrypt_file(config: Config, key: &Key) -> Result<()> {
+        let meta_path = meta_file_path(&config.path);
+        if !meta_path.exists() {
+            return Err(Error::IOError(format!(
+                "meta file {} doesn't exist",
+                meta_path.display()
+            )));
+        }
+        let mut encryptor = Encryptor::init(&config.path, key)?;
+        let (template, _) = codec_pair(Checksum::default().codec())?;
+        IOWriter::convert(
+            config,
+            Codec::AesEax,
+            |mm| {
+                // The meta is removed afterwards, don't save the key check of a legacy meta
+                encryptor.check_legacy_key(mm.records()?, true)?;
+                Ok((Box::new(encryptor.clone()), Box::new(encryptor)))
+            },
+            template,
+        )?;
+        // The nonce is useless for the plaintext file
+        fs::remove_file(&meta_path)
+            .map_err(|e| Error::IOError(format!("failed to remove {}: {e}", meta_path.display())))
+    }
+
+    /// Check the instance is encrypted with `key`, or not encrypted if it's None
+    #[cfg(feature = "encryption")]
+    pub fn check_key(&self, key: Option<&Key>) -> Result<()> {
+        match (&self.encryptor, key) {
+            (Some(encryptor), Some(key)) if encryptor.has_key(key) => Ok(()),
+            (Some(_), Some(_)) => Err(Error::WrongKey),
+            (None, None) => Ok(()),
+            (Some(_), None) => Err(Error::CodecMismatch(
+                "the instance is opened with encryption".to_string(),
+            )),
+            (None, Some(_)) => Err(Error::CodecMismatch(
+                "the instance is opened without encryption".to_string(),
+            )),
+        }
+    }
+
+    // Whether the changes can be written, see [WriteFailures::check_writable]
+    fn check_writable(&self) -> Result<()> {
+        if !self.is_valid {
+            return Err(InstanceClosed);
+        }
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
+        self.failures.check_writable()
+    }
+
+    pub fn failures(&self) -> &WriteFailures {
+        &self.failures
+    }
+
+    pub fn set_default_ttl(&mut self, ttl: Option<Duration>) {
+        self.default_ttl = ttl;
+    }
+
+    fn apply_default_ttl(&self, buffer: Buffer) -> Buffer {
+        match self.default_ttl {
+            Some(ttl) if !buffer.has_ttl() && !buffer.is_deleting() => buffer.with_ttl(ttl),
+            _ => buffer,
+        }
+    }
+
+    // Compress the value before it's stored, so it's kept compressed in memory and in the file
+    fn compress(&self, buffer: Buffer) -> Result<Buffer> {
+        #[cfg(feature = "compression")]
+        if let Some((algorithm, threshold)) = self.options.compression {
+            return buffer.compress(algorithm, threshold);
+        }
+        Ok(buffer)
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.recovery_report
+    }
+
+    pub fn observers(&self) -> &Observers {
+        &self.observers
+    }
+
     pub fn clear_data(&mut self) -> Result<()> {
         if !self.is_valid {
             warn!(LOG_TAG, "instance already closed");
             return Ok(());
         }
+        if self.options.read_only {
+            return Err(ReadOnly);
+        }
         self.is_valid = false;
         #[cfg(feature = "encryption")]
-        let meta_file = self.encryptor.meta_file_path.clone();
+        let meta_file = self
+            .encryptor
+            .as_ref()
+            .map(|encryptor| encryptor.meta_file_path.clone());
         let shared_kv = Arc::clone(&self.shared_kv);
+        let observers = self.observers.clone();
+        let failures = self.failures.clone();
         self.io_looper.call(move |writer| {
-            writer.remove_file()?;
-            shared_kv
-                .write()
-                .map_err(|e| Error::LockError(e.to_string()))?
-                .clear();
+            // Remove the nonce first, so other processes never pair it with a new file
             #[cfg(feature = "encryption")]
-            let _ = fs::remove_file(meta_file);
+            if let Some(meta_file) = meta_file {
+                let _ = fs::remove_file(meta_file);
+            }
+            writer.remove_file()?;
+            let cleared = std::mem::take(
+                &mut *shared_kv
+                    .write()
+                    .map_err(|e| Error::LockError(e.to_string()))?,
+            );
+            if !observers.is_empty() {
+                observers.notify(
+                    cleared
+                        .into_iter()
+                        .map(|(key, buffer)| Change::new(&key, Some(buffer), None))
+                        .collect(),
+                );
+            }
+            failures.clear()?;
             info!(LOG_TAG, "data cleared");
             Ok(())
         })?;
@@ -173,29 +616,45 @@ impl MmkvImpl {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::FileExt;
     use std::path::Path;
-    use std::sync::RwLock;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
     use std::{fs, thread};
 
-    use crate::Error::KeyNotFound;
+    use crossbeam_channel::unbounded;
+
+    use crate::Error::{DataInvalid, IOError, InstanceClosed, KeyNotFound};
     use crate::LogLevel::Debug;
-    use crate::MMKV;
-    use crate::core::buffer::Buffer;
-    use crate::core::config::Config;
+    use crate::core::buffer::{Buffer, DecodeResult, Decoder, Encoder};
+    use crate::core::config::{Checksum, Config, Options};
+    use crate::core::failure::WriteFailurePolicy;
+    use crate::core::header::{Codec, HEADER_LEN};
     use crate::core::memory_map::MemoryMap;
     use crate::core::mmkv_impl::MmkvImpl;
+    use crate::core::observer::Filter;
+    use crate::core::recovery::{RecoveryReport, RecoveryStrategy};
+    use crate::{Error, MMKV};
 
     #[cfg(feature = "encryption")]
-    const TEST_KEY: &str = "88C51C536176AD8A8EE4A06F62EE897E";
+    use crate::core::config::TEST_KEY;
 
     fn init(config: &Config) -> MmkvImpl {
         MMKV::set_log_level(Debug);
-        MmkvImpl::new(
-            config.try_clone().unwrap(),
-            #[cfg(feature = "encryption")]
-            TEST_KEY,
+        MmkvImpl::new(config.reopen().unwrap()).unwrap()
+    }
+
+    // Map the file again, it's replaced by a new file after trim
+    fn write_offset(config: &Config) -> usize {
+        let config = config.reopen().unwrap();
+        MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
         )
         .unwrap()
+        .write_offset()
     }
 
     #[test]
@@ -205,41 +664,40 @@ mod tests {
         let _ = fs::remove_file(file_path);
         assert!(!Path::new(file_path).exists());
         let _ = fs::remove_file(format!("{}.meta", file_path));
-        let config = &Config::new(Path::new(file_path), 100).unwrap();
-        let mm = MemoryMap::new(&config.file, 200).unwrap();
+        let config = &Config::new(Path::new(file_path), 128).unwrap();
         let mut mmkv = init(config);
-        mmkv.put("key1", Buffer::new("key1", 1)).unwrap(); // + 17
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap(); // + 20
         assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 25);
+        assert_eq!(write_offset(config), 44);
 
         mmkv = init(config);
-        mmkv.put("key2", Buffer::new("key2", 2)).unwrap(); // + 17
-        mmkv.put("key3", Buffer::new("key3", 3)).unwrap(); // + 17
-        mmkv.put("key1", Buffer::new("key1", 4)).unwrap(); // + 17
-        mmkv.put("key2", Buffer::new("key2", 5)).unwrap(); // + 17
+        mmkv.put("key2", Buffer::new("key2", 2)).unwrap(); // + 20
+        mmkv.put("key3", Buffer::new("key3", 3)).unwrap(); // + 20
+        mmkv.put("key1", Buffer::new("key1", 4)).unwrap(); // + 20
+        mmkv.put("key2", Buffer::new("key2", 5)).unwrap(); // + 20
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 93);
+        assert_eq!(write_offset(config), 124);
 
         mmkv = init(config);
-        mmkv.put("key1", Buffer::new("key1", 6)).unwrap(); // + 17, trim, 3 items remain
+        mmkv.put("key1", Buffer::new("key1", 6)).unwrap(); // + 20, trim, 3 items remain
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 59);
+        assert_eq!(write_offset(config), 84);
 
         mmkv = init(config);
         assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(6));
         assert_eq!(mmkv.get("key2").unwrap().parse::<i32>(), Ok(5));
         mmkv.put("key4", Buffer::new("key4", 4)).unwrap();
-        mmkv.put("key5", Buffer::new("key5", 5)).unwrap(); // 93
-        mmkv.put("key6", Buffer::new("key6", 6)).unwrap(); // expand, 110
+        mmkv.put("key5", Buffer::new("key5", 5)).unwrap(); // 124
+        mmkv.put("key6", Buffer::new("key6", 6)).unwrap(); // expand, 144
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 110);
-        assert_eq!(config.file_size().unwrap(), 200);
+        assert_eq!(write_offset(config), 144);
+        assert_eq!(config.reopen().unwrap().file_size().unwrap(), 256);
 
         mmkv = init(config);
         mmkv.put("key7", Buffer::new("key7", 7)).unwrap();
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 127);
+        assert_eq!(write_offset(config), 164);
 
         mmkv = init(config);
         mmkv.clear_data().unwrap();
@@ -252,44 +710,285 @@ mod tests {
         let file = "test_trim_and_expand_encrypt";
         let _ = fs::remove_file(file);
         let _ = fs::remove_file(format!("{file}.meta"));
-        let config = &Config::new(Path::new(file), 100).unwrap();
-        let mm = MemoryMap::new(&config.file, 200).unwrap();
+        let config = &Config::new(Path::new(file), 108).unwrap();
         let mut mmkv = init(config);
         mmkv.put("key1", Buffer::new("key1", 1)).unwrap(); // + 24
         assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 32);
+        assert_eq!(write_offset(config), 48);
 
         mmkv = init(config);
         mmkv.put("key2", Buffer::new("key2", 2)).unwrap(); // + 24
         mmkv.put("key3", Buffer::new("key3", 3)).unwrap(); // + 24
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 80);
+        assert_eq!(write_offset(config), 96);
 
         mmkv = init(config);
         mmkv.put("key1", Buffer::new("key1", 4)).unwrap(); // + 24 trim
         mmkv.put("key2", Buffer::new("key2", 5)).unwrap(); // + 24 trim
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 80);
+        assert_eq!(write_offset(config), 96);
 
         mmkv = init(config);
         assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(4));
         assert_eq!(mmkv.get("key2").unwrap().parse::<i32>(), Ok(5));
         mmkv.put("key4", Buffer::new("key4", 4)).unwrap(); // + 24
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 104);
-        assert_eq!(config.file_size().unwrap(), 200);
+        assert_eq!(write_offset(config), 120);
+        assert_eq!(config.reopen().unwrap().file_size().unwrap(), 216);
 
         mmkv = init(config);
         mmkv.put("key5", Buffer::new("key5", 5)).unwrap(); // + 24
         drop(mmkv);
-        assert_eq!(mm.write_offset(), 128);
+        assert_eq!(write_offset(config), 144);
 
         mmkv = init(config);
         mmkv.clear_data().unwrap();
         assert!(!Path::new(file).exists());
     }
 
+    #[test]
+    fn test_upgrade_legacy_file() {
+        let file = "test_upgrade_legacy_file";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{file}.meta"));
+        #[cfg(not(feature = "encryption"))]
+        let encoder = crate::core::crc::CrcEncoderDecoder;
+        #[cfg(feature = "encryption")]
+        let encoder = crate::core::encrypt::Encryptor::init(Path::new(file), &TEST_KEY).unwrap();
+        let mut records = vec![];
+        records.extend(encoder.encode_to_bytes(&Buffer::new("key1", 1), 0).unwrap());
+        records.extend(
+            encoder
+                .encode_to_bytes(&Buffer::new("key2", "2"), 1)
+                .unwrap(),
+        );
+        let mut content = (records.len() as u64).to_be_bytes().to_vec();
+        content.extend(&records);
+        content.resize(128, 0);
+        fs::write(file, content).unwrap();
+
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let mut mmkv = init(config);
+        let upgraded = fs::read(file).unwrap();
+        assert_eq!(upgraded[0..4], *b"MMKV");
+        // Upgraded with the codec of new files
+        assert_eq!(upgraded[6], config.options().codec() as u8);
+        mmkv.put("key3", Buffer::new("key3", 3)).unwrap();
+        drop(mmkv);
+
+        mmkv = init(config);
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        assert_eq!(
+            mmkv.get("key2").unwrap().parse::<String>(),
+            Ok("2".to_string())
+        );
+        assert_eq!(mmkv.get("key3").unwrap().parse::<i32>(), Ok(3));
+        mmkv.clear_data().unwrap();
+
+        // Trimming a legacy file only zeroed the len, the old records were left behind
+        let mut content = 0u64.to_be_bytes().to_vec();
+        content.extend(&records);
+        content.resize(128, 0xAB);
+        fs::write(file, content).unwrap();
+        let mut mmkv = init(config);
+        assert_eq!(mmkv.count(), Ok(0));
+        assert_eq!(fs::read(file).unwrap()[0..4], *b"MMKV");
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        drop(mmkv);
+        mmkv = init(config);
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        assert_eq!(mmkv.count(), Ok(1));
+        mmkv.clear_data().unwrap();
+    }
+
+    // Write 5 records and break the length of the third one, return the offsets of the records
+    fn write_corrupted(config: &Config) -> Vec<usize> {
+        let mut mmkv = init(config);
+        mmkv.clear_data().unwrap();
+        let mut mmkv = init(config);
+        for i in 1..=5 {
+            let key = format!("key{i}");
+            mmkv.put(&key, Buffer::new(&key, i)).unwrap();
+        }
+        drop(mmkv);
+        let content = fs::read(&config.path).unwrap();
+        let content_len =
+            u64::from_be_bytes(content[HEADER_LEN - 8..HEADER_LEN].try_into().unwrap());
+        let mut offsets = vec![HEADER_LEN];
+        while offsets.len() < 5 {
+            let offset = *offsets.last().unwrap();
+            let len = u32::from_be_bytes(content[offset..offset + 4].try_into().unwrap());
+            offsets.push(offset + 4 + len as usize);
+        }
+        assert!(offsets[4] < HEADER_LEN + content_len as usize);
+        let file = fs::OpenOptions::new().write(true).open(&config.path);
+        file.unwrap()
+            .write_at(&[0xff; 4], offsets[2] as u64)
+            .unwrap();
+        offsets
+    }
+
+    #[test]
+    fn test_recovery_strategy() {
+        let file = Path::new("test_recovery_strategy");
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file("test_recovery_strategy.meta");
+        let config_with = |recovery| {
+            let options = Options {
+                recovery,
+                ..Options::for_test(1024)
+            };
+            Config::with_options(file, options).unwrap()
+        };
+        let keys = |mmkv: &MmkvImpl| {
+            let mut keys = mmkv.keys().unwrap();
+            keys.sort();
+            keys
+        };
+
+        let config = &config_with(RecoveryStrategy::Fail);
+        write_corrupted(config);
+        let result = MmkvImpl::new(config.reopen().unwrap());
+        assert!(matches!(result, Err(DataInvalid)));
+
+        let config = &config_with(RecoveryStrategy::Resync);
+        let offsets = write_corrupted(config);
+        let mmkv = init(config);
+        assert_eq!(keys(&mmkv), ["key1", "key2", "key4", "key5"]);
+        let report = RecoveryReport {
+            records_lost: 1,
+            bytes_lost: offsets[3] - offsets[2],
+        };
+        assert_eq!(mmkv.recovery_report(), report);
+        drop(mmkv);
+        // The lost record is discarded by compaction
+        let mmkv = init(config);
+        assert!(mmkv.recovery_report().is_clean());
+        assert_eq!(keys(&mmkv), ["key1", "key2", "key4", "key5"]);
+        drop(mmkv);
+
+        let config = &config_with(RecoveryStrategy::Stop);
+        write_corrupted(config);
+        let mut mmkv = init(config);
+        assert_eq!(keys(&mmkv), ["key1", "key2"]);
+        assert_eq!(mmkv.recovery_report().records_lost, 1);
+        // The records appended after recovery are readable
+        mmkv.put("key6", Buffer::new("key6", 6)).unwrap();
+        drop(mmkv);
+        let mut mmkv = init(config);
+        assert!(mmkv.recovery_report().is_clean());
+        assert_eq!(keys(&mmkv), ["key1", "key2", "key6"]);
+        mmkv.clear_data().unwrap();
+    }
+
+    #[test]
+    fn test_multi_process() {
+        let file = "test_multi_process";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{file}.meta"));
+        let options = Options {
+            multi_process: true,
+            ..Options::for_test(1024)
+        };
+        let config = &Config::with_options(Path::new(file), options).unwrap();
+        // Each instance opens the file on its own, like the instances in two processes
+        let mut first = init(config);
+        let mut second = init(config);
+        let get = |mmkv: &MmkvImpl, key: &str| mmkv.get(key).and_then(|b| b.parse::<i32>());
+
+        first.put("key1", Buffer::new("key1", 1)).unwrap();
+        first.flush().unwrap();
+        assert_eq!(get(&second, "key1"), Ok(1));
+        second.put("key1", Buffer::new("key1", 2)).unwrap();
+        second.flush().unwrap();
+        assert_eq!(get(&first, "key1"), Ok(2));
+
+        // The file is expanded, then replaced by compaction in the first process
+        let value = vec![0u8; 64];
+        for i in 0..20 {
+            let key = format!("large{i}");
+            first
+                .put(&key, Buffer::new(&key, value.as_slice()))
+                .unwrap();
+        }
+        for i in 0..100 {
+            first.put("key2", Buffer::new("key2", i)).unwrap();
+        }
+        first.flush().unwrap();
+        assert_eq!(second.count(), Ok(22));
+        assert_eq!(get(&second, "key2"), Ok(99));
+
+        // The writes of the second process continue after the loaded records
+        second.delete("key1").unwrap();
+        second.put("key3", Buffer::new("key3", 3)).unwrap();
+        second.flush().unwrap();
+        assert_eq!(first.contains_key("key1"), Ok(false));
+        assert_eq!(get(&first, "key3"), Ok(3));
+        drop(first);
+        let mut reopened = init(config);
+        assert!(reopened.recovery_report().is_clean());
+        assert_eq!(reopened.count(), Ok(22));
+
+        reopened.clear_data().unwrap();
+        assert_eq!(second.count(), Ok(0));
+        second.clear_data().unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let file = "test_reload";
+        let backup = "test_reload_backup";
+        for path in [file, backup] {
+            let _ = fs::remove_file(path);
+            let _ = fs::remove_file(format!("{path}.meta"));
+        }
+        let restore = |from: &str, rename: bool| {
+            let meta = (format!("{from}.meta"), format!("{file}.meta"));
+            if rename {
+                fs::rename(from, file).unwrap();
+                let _ = fs::rename(meta.0, meta.1);
+            } else {
+                fs::copy(from, file).unwrap();
+                let _ = fs::copy(meta.0, meta.1);
+            }
+        };
+        let config = &Config::new(Path::new(file), 1024).unwrap();
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        mmkv.flush().unwrap();
+        fs::copy(file, backup).unwrap();
+        let _ = fs::copy(format!("{file}.meta"), format!("{backup}.meta"));
+        mmkv.put("key1", Buffer::new("key1", 2)).unwrap();
+        mmkv.put("key2", Buffer::new("key2", 2)).unwrap();
+        mmkv.flush().unwrap();
+
+        // Overwritten in place, the stale map is kept until reload
+        restore(backup, false);
+        assert_eq!(mmkv.get("key2").unwrap().parse::<i32>(), Ok(2));
+        mmkv.reload().unwrap();
+        assert!(mmkv.recovery_report().is_clean());
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        assert_eq!(mmkv.get("key2"), Err(KeyNotFound));
+        mmkv.put("key3", Buffer::new("key3", 3)).unwrap();
+        drop(mmkv);
+
+        // Replaced by rename, reloaded before the next read
+        let options = Options {
+            detect_changes: true,
+            ..Options::for_test(1024)
+        };
+        let config = &Config::with_options(Path::new(file), options).unwrap();
+        let mmkv = init(config);
+        assert_eq!(mmkv.get("key3").unwrap().parse::<i32>(), Ok(3));
+        restore(backup, true);
+        assert_eq!(mmkv.get("key3"), Err(KeyNotFound));
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        let mut mmkv = init(config);
+        assert_eq!(mmkv.keys(), Ok(vec!["key1".to_string()]));
+        mmkv.clear_data().unwrap();
+    }
+
     #[test]
     fn test_multi_thread_mmkv() {
         let file = "test_multi_thread_mmkv";
@@ -360,6 +1059,518 @@ mod tests {
         assert!(!Path::new(file).exists());
     }
 
+    #[test]
+    fn test_key_enumeration() {
+        let file = "test_key_enumeration";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let mut mmkv = init(config);
+        assert_eq!(mmkv.count(), Ok(0));
+        assert_eq!(mmkv.keys(), Ok(vec![]));
+
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        mmkv.put("key2", Buffer::new("key2", "2")).unwrap();
+        mmkv.put("key1", Buffer::new("key1", 3)).unwrap();
+        mmkv.delete("key2").unwrap();
+        mmkv.put("key3", Buffer::new("key3", true)).unwrap();
+        let mut keys = mmkv.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["key1".to_string(), "key3".to_string()]);
+        assert_eq!(mmkv.count(), Ok(2));
+        assert_eq!(mmkv.contains_key("key1"), Ok(true));
+        assert_eq!(mmkv.contains_key("key2"), Ok(false));
+        drop(mmkv);
+
+        mmkv = init(config);
+        assert_eq!(mmkv.count(), Ok(2));
+        assert_eq!(mmkv.contains_key("key3"), Ok(true));
+        mmkv.clear_data().unwrap();
+        assert_eq!(mmkv.count(), Err(InstanceClosed));
+        assert!(!Path::new(file).exists());
+    }
+
+    #[test]
+    fn test_batch() {
+        let file = "test_batch";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        mmkv.put("key2", Buffer::new("key2", 2)).unwrap();
+        mmkv.batch(vec![
+            Buffer::new("key1", 10),
+            Buffer::deleted_buffer("key2"),
+            Buffer::deleted_buffer("missing"),
+            Buffer::new("key3", "3"),
+        ])
+        .unwrap();
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(10));
+        assert_eq!(mmkv.get("key2"), Err(KeyNotFound));
+        assert_eq!(
+            mmkv.get("key3").unwrap().parse::<String>(),
+            Ok("3".to_string())
+        );
+        drop(mmkv);
+
+        mmkv = init(config);
+        assert_eq!(mmkv.count(), Ok(2));
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(10));
+        assert_eq!(mmkv.get("key2"), Err(KeyNotFound));
+        assert_eq!(
+            mmkv.get("key3").unwrap().parse::<String>(),
+            Ok("3".to_string())
+        );
+        mmkv.clear_data().unwrap();
+        assert!(!Path::new(file).exists());
+    }
+
+    #[test]
+    fn test_corrupted_batch_is_dropped_entirely() {
+        let file = "test_corrupted_batch_is_dropped_entirely";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 256).unwrap();
+        let mm = MemoryMap::new(&config.file, 256, true, Codec::legacy()).unwrap();
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        mmkv.batch(vec![Buffer::new("key1", 10), Buffer::new("key2", 20)])
+            .unwrap();
+        drop(mmkv);
+
+        // Flip the last byte of the batch record
+        let last = mm.write_offset() as u64 - 1;
+        let mut byte = [0u8];
+        config.file.read_exact_at(&mut byte, last).unwrap();
+        config.file.write_all_at(&[!byte[0]], last).unwrap();
+
+        mmkv = init(config);
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        assert_eq!(mmkv.get("key2"), Err(KeyNotFound));
+        mmkv.clear_data().unwrap();
+        assert!(!Path::new(file).exists());
+    }
+
+    #[test]
+    fn test_observers() {
+        let file = "test_observers";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        let (sender, receiver) = unbounded();
+        let subscription = mmkv
+            .observers()
+            .subscribe(Filter::All, move |change| {
+                let old = change
+                    .old_value()
+                    .map(|entry| entry.parse::<i32>().unwrap());
+                let new = change
+                    .new_value()
+                    .map(|entry| entry.parse::<i32>().unwrap());
+                sender.send((change.key().to_string(), old, new)).unwrap();
+            })
+            .unwrap();
+        let recv = || receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        mmkv.put("key1", Buffer::new("key1", 2)).unwrap();
+        assert_eq!(recv(), ("key1".to_string(), Some(1), Some(2)));
+        mmkv.delete("key1").unwrap();
+        assert_eq!(recv(), ("key1".to_string(), Some(2), None));
+        mmkv.delete("key1").unwrap();
+        mmkv.batch(vec![Buffer::new("key2", 3)]).unwrap();
+        assert_eq!(recv(), ("key2".to_string(), None, Some(3)));
+        mmkv.clear_data().unwrap();
+        assert_eq!(recv(), ("key2".to_string(), Some(3), None));
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(subscription);
+        assert!(!Path::new(file).exists());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let file = "test_ttl";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let mut mmkv = init(config);
+        let ttl = Duration::from_millis(100);
+        mmkv.put("key1", Buffer::new("key1", 1).with_ttl(ttl))
+            .unwrap();
+        mmkv.set_default_ttl(Some(ttl));
+        mmkv.put("key2", Buffer::new("key2", 2)).unwrap();
+        mmkv.set_default_ttl(None);
+        mmkv.put("key3", Buffer::new("key3", 3)).unwrap();
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        assert_eq!(mmkv.get("key2").unwrap().parse::<i32>(), Ok(2));
+        assert_eq!(mmkv.count(), Ok(3));
+        drop(mmkv);
+
+        thread::sleep(ttl);
+        mmkv = init(config);
+        assert_eq!(mmkv.get("key1"), Err(KeyNotFound));
+        assert_eq!(mmkv.get("key2"), Err(KeyNotFound));
+        assert_eq!(mmkv.contains_key("key1"), Ok(false));
+        assert_eq!(mmkv.keys(), Ok(vec!["key3".to_string()]));
+        assert_eq!(mmkv.count(), Ok(1));
+        mmkv.put("key1", Buffer::new("key1", 10)).unwrap();
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(10));
+        mmkv.clear_data().unwrap();
+        assert!(!Path::new(file).exists());
+    }
+
+    #[test]
+    fn test_put_sync_and_flush() {
+        let file = "test_put_sync_and_flush";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let mm = MemoryMap::new(&config.file, 128, true, Codec::legacy()).unwrap();
+        let mut mmkv = init(config);
+        let completion = mmkv.put_sync("key1", Buffer::new("key1", 1)).unwrap();
+        assert_eq!(completion.wait(), Ok(()));
+        let written = mm.write_offset();
+        assert!(written > mm.content_start_offset());
+
+        mmkv.put("key2", Buffer::new("key2", 2)).unwrap();
+        mmkv.flush().unwrap();
+        assert!(mm.write_offset() > written);
+
+        mmkv.io_looper.quit().unwrap();
+        assert!(mmkv.flush().is_err());
+        assert!(mmkv.put_sync("key3", Buffer::new("key3", 3)).is_err());
+        assert_eq!(mmkv.get("key3"), Err(KeyNotFound));
+
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+    }
+
+    struct FailingEncoder;
+
+    impl Encoder for FailingEncoder {
+        fn encode_to_bytes(&self, _: &Buffer, _: u32) -> crate::Result<Vec<u8>> {
+            Err(IOError("disk full".to_string()))
+        }
+
+        fn codec(&self) -> Codec {
+            Codec::legacy()
+        }
+
+        fn for_new_file(&self, _: &Path, _: Checksum) -> crate::Result<Box<dyn Encoder>> {
+            Ok(Box::new(FailingEncoder))
+        }
+
+        fn for_existing_file(&self, _: &Path, _: Codec) -> crate::Result<Box<dyn Encoder>> {
+            Ok(Box::new(FailingEncoder))
+        }
+
+        fn decoder(&self) -> Box<dyn Decoder> {
+            Box::new(FailingEncoder)
+        }
+    }
+
+    impl Decoder for FailingEncoder {
+        fn decode_bytes(&self, _: &[u8], _: u32) -> crate::Result<DecodeResult> {
+            Err(IOError("disk full".to_string()))
+        }
+    }
+
+    fn break_writer(mmkv: &MmkvImpl) {
+        mmkv.io_looper
+            .call(|writer| {
+                writer.set_encoder(Box::new(FailingEncoder));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_write_failure_policy() {
+        let file = "test_write_failure_policy";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 128).unwrap();
+        let disk_full = IOError("disk full".to_string());
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        let (sender, receiver) = unbounded();
+        mmkv.failures()
+            .set_callback(Some(Arc::new(move |e: &Error| {
+                sender.send(e.clone()).unwrap();
+            })))
+            .unwrap();
+        break_writer(&mmkv);
+
+        // Keep in memory by default
+        mmkv.put("key1", Buffer::new("key1", 2)).unwrap();
+        assert_eq!(mmkv.flush(), Err(disk_full.clone()));
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)),
+            Ok(disk_full.clone())
+        );
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(2));
+
+        mmkv.failures().clear().unwrap();
+        mmkv.failures()
+            .set_policy(WriteFailurePolicy::Rollback)
+            .unwrap();
+        let (change_sender, change_receiver) = unbounded();
+        let subscription = mmkv
+            .observers()
+            .subscribe(Filter::All, move |change| {
+                let old = change
+                    .old_value()
+                    .map(|entry| entry.parse::<i32>().unwrap());
+                let new = change
+                    .new_value()
+                    .map(|entry| entry.parse::<i32>().unwrap());
+                change_sender
+                    .send((change.key().to_string(), old, new))
+                    .unwrap();
+            })
+            .unwrap();
+        mmkv.put("key1", Buffer::new("key1", 3)).unwrap();
+        assert_eq!(mmkv.flush(), Err(disk_full.clone()));
+        mmkv.put("key2", Buffer::new("key2", 3)).unwrap();
+        assert_eq!(mmkv.flush(), Err(disk_full.clone()));
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(2));
+        assert_eq!(mmkv.get("key2"), Err(KeyNotFound));
+        mmkv.delete("key1").unwrap();
+        assert_eq!(mmkv.flush(), Err(disk_full.clone()));
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(2));
+        assert_eq!(
+            mmkv.put_sync("key3", Buffer::new("key3", 3))
+                .unwrap()
+                .wait(),
+            Err(disk_full.clone())
+        );
+        assert_eq!(mmkv.get("key3"), Err(KeyNotFound));
+        // The observers are told of each reverted change right after the change
+        let changes: Vec<_> = std::iter::from_fn(|| {
+            change_receiver
+                .recv_timeout(Duration::from_millis(100))
+                .ok()
+        })
+        .collect();
+        let expected = [
+            ("key1", Some(2), Some(3)),
+            ("key1", Some(3), Some(2)),
+            ("key2", None, Some(3)),
+            ("key2", Some(3), None),
+            ("key1", Some(2), None),
+            ("key1", None, Some(2)),
+            ("key3", None, Some(3)),
+            ("key3", Some(3), None),
+        ]
+        .map(|(key, old, new)| (key.to_string(), old, new));
+        assert_eq!(changes, expected);
+        drop(subscription);
+
+        mmkv.failures().clear().unwrap();
+        mmkv.failures()
+            .set_policy(WriteFailurePolicy::ReadOnly)
+            .unwrap();
+        mmkv.put("key4", Buffer::new("key4", 4)).unwrap();
+        assert_eq!(mmkv.flush(), Err(disk_full.clone()));
+        assert_eq!(mmkv.get("key4").unwrap().parse::<i32>(), Ok(4));
+        assert_eq!(
+            mmkv.put("key5", Buffer::new("key5", 5)),
+            Err(disk_full.clone())
+        );
+        assert_eq!(mmkv.delete("key4"), Err(disk_full.clone()));
+        assert_eq!(mmkv.get("key5"), Err(KeyNotFound));
+        mmkv.failures().clear().unwrap();
+        mmkv.put("key5", Buffer::new("key5", 5)).unwrap();
+
+        mmkv.clear_data().unwrap();
+        assert_eq!(mmkv.failures().last_error(), None);
+        assert!(!Path::new(file).exists());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_legacy_meta_with_corrupted_first_record() {
+        use crate::core::encrypt::{KEY_CHECK_LEN, NONCE_LEN};
+
+        let file = "test_legacy_meta_with_corrupted_first_record";
+        let meta_file = format!("{file}.meta");
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(&meta_file);
+        let mut options = Options::for_test(1024);
+        options.recovery = RecoveryStrategy::Resync;
+        let config = &Config::with_options(Path::new(file), options).unwrap();
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        mmkv.put("key2", Buffer::new("key2", 2)).unwrap();
+        mmkv.flush().unwrap();
+        drop(mmkv);
+        let meta = fs::read(&meta_file).unwrap();
+        // The meta files of the older versions only have the nonce
+        fs::write(&meta_file, &meta[..NONCE_LEN]).unwrap();
+        // Break the last byte of the first record, its length is kept
+        let content = fs::read(file).unwrap();
+        let first_len = u32::from_be_bytes(content[HEADER_LEN..HEADER_LEN + 4].try_into().unwrap());
+        let offset = HEADER_LEN + 4 + first_len as usize - 1;
+        let file_handle = fs::OpenOptions::new().write(true).open(file).unwrap();
+        file_handle
+            .write_at(&[content[offset] ^ 1], offset as u64)
+            .unwrap();
+
+        let mmkv = init(config);
+        assert_eq!(mmkv.recovery_report.records_lost, 1);
+        assert_eq!(mmkv.get("key1"), Err(KeyNotFound));
+        assert_eq!(mmkv.get("key2").unwrap().parse::<i32>(), Ok(2));
+        // The repaired file is written with a new meta which has the key check
+        assert_eq!(
+            fs::read(&meta_file).unwrap().len(),
+            NONCE_LEN + KEY_CHECK_LEN
+        );
+        drop(mmkv);
+        let mmkv = init(config);
+        assert!(mmkv.recovery_report.is_clean());
+        assert_eq!(mmkv.get("key2").unwrap().parse::<i32>(), Ok(2));
+        drop(mmkv);
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(&meta_file);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_broken_meta_file() {
+        use crate::core::config::Key;
+        use crate::core::encrypt::NONCE_LEN;
+
+        let file = "test_broken_meta_file";
+        let meta_file = format!("{file}.meta");
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(&meta_file);
+        let config = &Config::new(Path::new(file), 1024).unwrap();
+        let mut mmkv = init(config);
+        mmkv.put("key1", Buffer::new("key1", 1)).unwrap();
+        mmkv.flush().unwrap();
+        drop(mmkv);
+        let data = fs::read(file).unwrap();
+        let meta = fs::read(&meta_file).unwrap();
+
+        // A new nonce can't decrypt the records
+        fs::remove_file(&meta_file).unwrap();
+        assert!(matches!(
+            MmkvImpl::new(config.reopen().unwrap()),
+            Err(Error::IOError(_))
+        ));
+        assert!(!fs::exists(&meta_file).unwrap());
+        for broken in [&meta[..4], &[meta.as_slice(), &[0; 4]].concat()] {
+            fs::write(&meta_file, broken).unwrap();
+            assert!(matches!(
+                MmkvImpl::new(config.reopen().unwrap()),
+                Err(DataInvalid)
+            ));
+            assert_eq!(fs::read(&meta_file).unwrap(), broken);
+            assert!(matches!(
+                MmkvImpl::decrypt_file(config.reopen().unwrap(), &TEST_KEY),
+                Err(DataInvalid)
+            ));
+            assert_eq!(fs::read(&meta_file).unwrap(), broken);
+        }
+        assert_eq!(fs::read(file).unwrap(), data);
+
+        // The meta files of the older versions only have the nonce
+        let legacy = &meta[..NONCE_LEN];
+        fs::write(&meta_file, legacy).unwrap();
+        let wrong_key = Key::Aes128([0x0F; 16]);
+        let mut options = config.options().clone();
+        options.key = Some(wrong_key.clone());
+        let wrong_config = &Config::with_options(Path::new(file), options).unwrap();
+        assert!(matches!(
+            MmkvImpl::new(wrong_config.reopen().unwrap()),
+            Err(Error::WrongKey)
+        ));
+        assert!(matches!(
+            MmkvImpl::decrypt_file(config.reopen().unwrap(), &wrong_key),
+            Err(Error::WrongKey)
+        ));
+        assert_eq!(fs::read(&meta_file).unwrap(), legacy);
+        assert_eq!(fs::read(file).unwrap(), data);
+
+        fs::write(&meta_file, &meta).unwrap();
+        let mmkv = init(config);
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        drop(mmkv);
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(&meta_file);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypt_and_decrypt_file() {
+        use crate::Error::{CodecMismatch, WrongKey};
+        use crate::core::checksum::codec_pair;
+        use crate::core::config::{Key, meta_file_path};
+
+        let file = "test_encrypt_and_decrypt_file";
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(format!("{}.meta", file));
+        let config = &Config::new(Path::new(file), 256).unwrap();
+        let (encoder, decoder) = codec_pair(Codec::Crc32c).unwrap();
+        let mut mm = MemoryMap::new(&config.file, 256, true, Codec::Crc32c).unwrap();
+        let buffers = [Buffer::new("key1", 1), Buffer::new("key2", "2")];
+        for (position, buffer) in buffers.iter().enumerate() {
+            let bytes = encoder.encode_to_bytes(buffer, position as u32).unwrap();
+            mm.append(&bytes).unwrap();
+        }
+        drop(mm);
+        assert!(matches!(
+            MmkvImpl::new(config.reopen().unwrap()),
+            Err(CodecMismatch(_))
+        ));
+
+        MmkvImpl::encrypt_file(config.reopen().unwrap(), &TEST_KEY).unwrap();
+        assert!(meta_file_path(&config.path).exists());
+        assert!(matches!(
+            MmkvImpl::encrypt_file(config.reopen().unwrap(), &TEST_KEY),
+            Err(CodecMismatch(_))
+        ));
+        // Nothing changes with a wrong key
+        let encrypted = fs::read(file).unwrap();
+        assert_eq!(
+            MmkvImpl::decrypt_file(config.reopen().unwrap(), &Key::Aes128([0x0F; 16])),
+            Err(WrongKey)
+        );
+        assert_eq!(fs::read(file).unwrap(), encrypted);
+        let mmkv = init(config);
+        assert_eq!(mmkv.count(), Ok(2));
+        assert_eq!(mmkv.get("key1").unwrap().parse::<i32>(), Ok(1));
+        drop(mmkv);
+
+        MmkvImpl::decrypt_file(config.reopen().unwrap(), &TEST_KEY).unwrap();
+        assert!(!meta_file_path(&config.path).exists());
+        let config = config.reopen().unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::Crc32c,
+        )
+        .unwrap();
+        assert_eq!(mm.codec(), Codec::Crc32c);
+        let (kv_map, _, report) = mm
+            .iter(RecoveryStrategy::Stop, |bytes, position| {
+                decoder.decode_bytes(bytes, position)
+            })
+            .into_map()
+            .unwrap();
+        assert!(report.is_clean());
+        assert_eq!(
+            kv_map.get("key2").unwrap().parse::<String>(),
+            Ok("2".to_string())
+        );
+        assert_eq!(kv_map.len(), 2);
+        drop(mm);
+        let _ = fs::remove_file(file);
+    }
+
     #[test]
     fn test_post_failure_rolls_back_shared_state() {
         let file = "test_post_failure_rolls_back_shared_state";
@@ -367,6 +1578,20 @@ mod tests {
         let _ = fs::remove_file(format!("{}.meta", file));
         let config = &Config::new(Path::new(file), 128).unwrap();
         let mut mmkv = init(config);
+        let (sender, receiver) = unbounded();
+        let _subscription = mmkv
+            .observers()
+            .subscribe(Filter::All, move |change| {
+                let old = change
+                    .old_value()
+                    .map(|entry| entry.parse::<i32>().unwrap());
+                let new = change
+                    .new_value()
+                    .map(|entry| entry.parse::<i32>().unwrap());
+                sender.send((old, new)).unwrap();
+            })
+            .unwrap();
+        let recv = || receiver.recv_timeout(Duration::from_secs(1)).unwrap();
 
         mmkv.io_looper.quit().unwrap();
         assert!(
@@ -374,6 +1599,8 @@ mod tests {
                 .is_err()
         );
         assert_eq!(mmkv.get("rollback_key"), Err(KeyNotFound));
+        assert_eq!(recv(), (None, Some(1)));
+        assert_eq!(recv(), (Some(1), None));
 
         let _ = fs::remove_file(file);
         let _ = fs::remove_file(format!("{}.meta", file));
@@ -1,12 +1,21 @@
 pub mod buffer;
+mod checksum;
+pub mod compression;
 pub mod config;
-#[cfg(not(feature = "encryption"))]
 mod crc;
 #[cfg(feature = "encryption")]
 mod encrypt;
+pub mod entry;
+pub mod failure;
+#[cfg(fuzzing)]
+pub mod fuzz;
+mod header;
 pub mod io_looper;
 mod iter;
 mod memory_map;
 pub mod mmkv_impl;
+pub mod observer;
+pub mod recovery;
 mod shared_state;
+pub mod transaction;
 mod writer;
@@ -0,0 +1,221 @@
+use crate::Error::LockError;
+use crate::Result;
+use crate::core::buffer::Buffer;
+use crate::core::entry::Entry;
+use crate::core::io_looper::{Executor, IOLooper};
+use once_cell::sync::Lazy;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+
+const LOG_TAG: &str = "MMKV:Observer";
+
+type Callback = Arc<dyn Fn(&Change) + Send + Sync>;
+
+/// A change of one key, see [MMKV::on_change](crate::MMKV::on_change)
+#[derive(Debug, Clone)]
+pub struct Change {
+    key: String,
+    old: Option<Entry>,
+    new: Option<Entry>,
+}
+
+impl Change {
+    pub fn new(key: &str, old: Option<Buffer>, new: Option<Buffer>) -> Self {
+        Change {
+            key: key.to_string(),
+            old: old.filter(|buffer| !buffer.is_expired()).map(Entry::new),
+            new: new.map(Entry::new),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The value before this change, `None` if the key didn't exist
+    pub fn old_value(&self) -> Option<&Entry> {
+        self.old.as_ref()
+    }
+
+    /// The value after this change, `None` if the key was deleted
+    pub fn new_value(&self) -> Option<&Entry> {
+        self.new.as_ref()
+    }
+}
+
+pub enum Filter {
+    All,
+    Key(String),
+    Prefix(String),
+}
+
+impl Filter {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Key(target) => target == key,
+            Filter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+struct Observer {
+    id: u64,
+    filter: Filter,
+    callback: Callback,
+}
+
+type ObserverList = RwLock<Vec<Observer>>;
+
+/// Observers registered on one instance, the callbacks are called in a dedicated thread,
+/// so they never run under the lock of the instance.
+#[derive(Clone, Default)]
+pub struct Observers(Arc<ObserverList>);
+
+/// Returned by [MMKV::on_change](crate::MMKV::on_change), the callback is unregistered
+/// when the subscription dropped.
+#[must_use = "the callback is unregistered when the subscription dropped"]
+pub struct Subscription {
+    id: u64,
+    observers: Weak<ObserverList>,
+}
+
+struct Dispatcher;
+
+impl Executor for Dispatcher {}
+
+static DISPATCHER: Lazy<IOLooper<Dispatcher>> = Lazy::new(|| IOLooper::new(Dispatcher));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Observers {
+    pub fn subscribe<F>(&self, filter: Filter, callback: F) -> Result<Subscription>
+    where
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.0
+            .write()
+            .map_err(|e| LockError(e.to_string()))?
+            .push(Observer {
+                id,
+                filter,
+                callback: Arc::new(callback),
+            });
+        Ok(Subscription {
+            id,
+            observers: Arc::downgrade(&self.0),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().map(|list| list.is_empty()).unwrap_or(true)
+    }
+
+    /// Post the changes to the dispatcher thread, the callbacks are selected at this moment
+    pub fn notify(&self, changes: Vec<Change>) {
+        let jobs: Vec<(Callback, Change)> = match self.0.read() {
+            Ok(list) => changes
+                .into_iter()
+                .flat_map(|change| {
+                    list.iter()
+                        .filter(|observer| observer.filter.matches(&change.key))
+                        .map(|observer| (Arc::clone(&observer.callback), change.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            Err(e) => {
+                error!(LOG_TAG, "failed to read observers: {:?}", e);
+                return;
+            }
+        };
+        if jobs.is_empty() {
+            return;
+        }
+        dispatch(move || {
+            for (callback, change) in jobs {
+                if catch_unwind(AssertUnwindSafe(|| callback(&change))).is_err() {
+                    error!(LOG_TAG, "observer panicked for key {}", change.key);
+                }
+            }
+        });
+    }
+}
+
+/// Run the job in the dispatcher thread, which is shared by all instances
+pub fn dispatch<F: FnOnce() + Send + 'static>(job: F) {
+    let result = DISPATCHER.post(move |_| {
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!(LOG_TAG, "dispatched job panicked");
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        error!(LOG_TAG, "failed to dispatch job: {:?}", e);
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(observers) = self.observers.upgrade() {
+            match observers.write() {
+                Ok(mut list) => list.retain(|observer| observer.id != self.id),
+                Err(e) => error!(LOG_TAG, "failed to remove observer: {:?}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::buffer::Buffer;
+    use crate::core::observer::{Change, Filter, Observers};
+    use crossbeam_channel::unbounded;
+    use std::time::Duration;
+
+    #[test]
+    fn test_notify_matched_observers() {
+        let observers = Observers::default();
+        let (sender, receiver) = unbounded();
+        let subscriptions = [
+            (Filter::All, "all"),
+            (Filter::Key("user".to_string()), "key"),
+            (Filter::Prefix("user_".to_string()), "prefix"),
+        ]
+        .map(|(filter, name)| {
+            let sender = sender.clone();
+            observers
+                .subscribe(filter, move |change| {
+                    sender.send((name, change.key().to_string())).unwrap();
+                })
+                .unwrap()
+        });
+        let recv = || receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        observers.notify(vec![Change::new(
+            "user",
+            None,
+            Some(Buffer::new("user", 1)),
+        )]);
+        let mut received = vec![recv(), recv()];
+        received.sort();
+        assert_eq!(
+            received,
+            vec![("all", "user".into()), ("key", "user".into())]
+        );
+
+        observers.notify(vec![Change::new("user_name", None, None)]);
+        let mut received = vec![recv(), recv()];
+        received.sort();
+        assert_eq!(
+            received,
+            vec![("all", "user_name".into()), ("prefix", "user_name".into())]
+        );
+
+        drop(subscriptions);
+        assert!(observers.is_empty());
+        observers.notify(vec![Change::new("user", None, None)]);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+/// How to load a file with corrupted records, see [MMKVBuilder::recovery](crate::MMKVBuilder::recovery)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RecoveryStrategy {
+    /// Drop the records which fail the integrity check,
+    /// stop at the first record whose length is unreadable and discard the rest of the file
+    #[default]
+    Stop,
+    /// Drop the records which fail the integrity check,
+    /// scan for the next valid record after a record whose length is unreadable.
+    ///
+    /// An encrypted file can only resync if a single record is lost,
+    /// the records after several lost records can't be decrypted.
+    Resync,
+    /// Fail to open with [Error::DataInvalid](crate::Error::DataInvalid) if any record is lost
+    Fail,
+}
+
+/**
+What was lost while loading the file, see [MMKV::recovery_report](crate::MMKV::recovery_report).
+
+A file with lost records is compacted once loaded, so the lost bytes are discarded
+and the report of the next load is clean.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// The number of records which fail to decode,
+    /// an unreadable range skipped or discarded at once counts as one record
+    pub records_lost: usize,
+    /// The number of bytes of the lost records
+    pub bytes_lost: usize,
+}
+
+impl RecoveryReport {
+    /// Whether all the records are loaded
+    pub fn is_clean(&self) -> bool {
+        self.records_lost == 0
+    }
+
+    pub(crate) fn lose(&mut self, bytes: usize) {
+        self.records_lost += 1;
+        self.bytes_lost += bytes;
+    }
+}
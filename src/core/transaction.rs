@@ -0,0 +1,39 @@
+use crate::core::buffer::{Buffer, ProvideTypeToken, ToBytes};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Collects changes for [MMKV::batch](crate::MMKV::batch), the changes are applied
+/// after the batch closure returns. If a key is changed multiple times, the last change wins.
+#[derive(Default)]
+pub struct Transaction {
+    changes: HashMap<String, Buffer>,
+}
+
+impl Transaction {
+    /// See [MMKV::put](crate::MMKV::put)
+    pub fn put<T: ProvideTypeToken + ToBytes>(&mut self, key: &str, value: T) {
+        self.changes
+            .insert(key.to_string(), Buffer::new(key, value));
+    }
+
+    /// See [MMKV::put_with_ttl](crate::MMKV::put_with_ttl)
+    pub fn put_with_ttl<T: ProvideTypeToken + ToBytes>(
+        &mut self,
+        key: &str,
+        value: T,
+        ttl: Duration,
+    ) {
+        self.changes
+            .insert(key.to_string(), Buffer::new(key, value).with_ttl(ttl));
+    }
+
+    /// See [MMKV::delete](crate::MMKV::delete)
+    pub fn delete(&mut self, key: &str) {
+        self.changes
+            .insert(key.to_string(), Buffer::deleted_buffer(key));
+    }
+
+    pub fn into_changes(self) -> Vec<Buffer> {
+        self.changes.into_values().collect()
+    }
+}
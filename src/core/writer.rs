@@ -1,10 +1,20 @@
+#[cfg(feature = "encryption")]
+use crate::core::buffer::Decoder;
 use crate::core::buffer::{Buffer, Encoder};
-use crate::core::config::Config;
+use crate::core::config::{Config, FileLock};
+use crate::core::header::Codec;
 use crate::core::io_looper::Executor;
+use crate::core::iter::apply;
 use crate::core::memory_map::MemoryMap;
+use crate::core::recovery::RecoveryReport;
+#[cfg(feature = "encryption")]
+use crate::core::recovery::RecoveryStrategy;
 use crate::core::shared_state::SharedKvMap;
+#[cfg(feature = "encryption")]
+use crate::core::shared_state::new_shared_kv_map;
 use crate::{Error, Result};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Instant;
 
 const LOG_TAG: &str = "MMKV:IO";
@@ -16,6 +26,9 @@ pub struct IOWriter {
     need_trim: bool,
     shared_kv: SharedKvMap,
     encoder: Box<dyn Encoder>,
+    // The sequence and the write offset of the file when the map was last in sync with it
+    sequence: u64,
+    synced_offset: usize,
 }
 
 impl Executor for IOWriter {}
@@ -28,6 +41,8 @@ impl IOWriter {
         shared_kv: SharedKvMap,
         encoder: Box<dyn Encoder>,
     ) -> Self {
+        let sequence = mm.sequence();
+        let synced_offset = mm.write_offset();
         IOWriter {
             config,
             mm,
@@ -35,20 +50,22 @@ impl IOWriter {
             need_trim: false,
             shared_kv,
             encoder,
+            sequence,
+            synced_offset,
         }
     }
 
-    // Flash the data to file, always running in one io thread, so don't need lock here
+    // Flash the data to file, always running in one io thread,
+    // the file is only locked if it's shared by processes
     pub fn write(&mut self, buffer: Buffer, duplicated: bool) -> Result<()> {
+        let _lock = self.lock(true, Some(&buffer))?;
         let data = self.encoder.encode_to_bytes(&buffer, self.position)?;
         let target_end = data.len() + self.mm.write_offset();
         if duplicated {
             self.need_trim = true;
         }
         if target_end <= self.mm.len() {
-            self.mm.append(&data)?;
-            self.position += 1;
-            return Ok(());
+            return self.append(&data);
         }
         if self.need_trim {
             let time_start = Instant::now();
@@ -71,45 +88,308 @@ impl IOWriter {
         } else {
             // expand and write
             self.ensure_capacity(data.len())?;
-            self.mm.append(&data)?;
-            self.position += 1;
+            self.append(&data)?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.mm.append(data)?;
+        self.position += 1;
+        // Let other processes know there are new records
+        self.mm.set_sequence(self.mm.sequence().wrapping_add(1));
+        self.mark_synced();
+        Ok(())
+    }
+
+    fn mark_synced(&mut self) {
+        self.sequence = self.mm.sequence();
+        self.synced_offset = self.mm.write_offset();
+    }
+
+    /// Apply the changes written by other processes to the map if the file is shared by processes,
+    /// or reload the file if it's changed by others and [detect_changes](Options::detect_changes)
+    pub fn sync(&mut self) -> Result<()> {
+        self.lock(false, None).map(|_| ())
+    }
+
+    /**
+    Lock the file if it's shared by processes, and apply the changes written by other processes
+    since the last sync to the map, the `pending` record of this process is applied after them.
+
+    The lock is held until the returned [FileLock] is dropped, None if the file isn't shared.
+    */
+    fn lock(&mut self, exclusive: bool, pending: Option<&Buffer>) -> Result<Option<FileLock>> {
+        if !self.config.options().multi_process {
+            if self.config.options().detect_changes && self.is_modified()? {
+                info!(LOG_TAG, "file changed by others, reload it");
+                self.config.reopen_file()?;
+                let report = self.reload_file(pending)?;
+                self.repair(&report);
+            }
+            return Ok(None);
+        }
+        let (lock, reopened) = self.config.lock(exclusive)?;
+        if reopened {
+            self.reload_file(pending)?;
+        } else {
+            self.catch_up(pending)?;
+        }
+        Ok(Some(lock))
+    }
+
+    // Whether the file is replaced or written by others since the last sync
+    fn is_modified(&self) -> Result<bool> {
+        Ok(self.config.is_replaced()?
+            || self.config.file_size()? as usize != self.mm.len()
+            || self.mm.sequence() != self.sequence
+            || self.mm.write_offset() != self.synced_offset)
+    }
+
+    /// Load the file at the path again, which may be replaced or modified by others,
+    /// return what's lost while loading it
+    pub fn reload(&mut self) -> Result<RecoveryReport> {
+        self.config.reopen_file()?;
+        // Hold the lock while the loaded file is repaired
+        let _lock = if self.config.options().multi_process {
+            Some(self.config.lock(true)?.0)
+        } else {
+            None
+        };
+        let report = self.reload_file(None)?;
+        self.repair(&report);
+        Ok(report)
+    }
+
+    /// Rewrite the loaded file if it has lost records or it needs upgrade,
+    /// the failure is only logged, the file is still readable and writable
+    pub fn repair(&mut self, report: &RecoveryReport) {
+        if self.config.options().read_only {
+            return;
+        }
+        if !report.is_clean() {
+            // Drop the corrupted bytes, so the records appended later are readable
+            if let Err(e) = self.discard_lost() {
+                error!(LOG_TAG, "failed to discard lost records, reason: {:?}", e);
+            }
+        } else if let Err(e) = self.upgrade() {
+            // Try again next time
+            error!(LOG_TAG, "failed to upgrade file, reason: {:?}", e);
+        }
+    }
+
+    // Apply the records appended by other processes since the last sync
+    fn catch_up(&mut self, pending: Option<&Buffer>) -> Result<()> {
+        if self.config.file_size()? as usize != self.mm.len() {
+            // Expanded by another process
+            self.mm = IOWriter::map(&self.config, self.encoder.codec())?;
+        }
+        if self.mm.sequence() == self.sequence {
+            return Ok(());
+        }
+        if self.mm.write_offset() < self.synced_offset {
+            return self.reload_file(pending).map(|_| ());
+        }
+        verbose!(
+            LOG_TAG,
+            "catch up from offset {} to {}",
+            self.synced_offset,
+            self.mm.write_offset()
+        );
+        let decoder = self.encoder.decoder();
+        let shared_kv = Arc::clone(&self.shared_kv);
+        let mut kv_map = shared_kv
+            .write()
+            .map_err(|e| Error::LockError(e.to_string()))?;
+        let (position, _) = self
+            .mm
+            .iter_from(
+                self.synced_offset,
+                self.position,
+                self.config.options().recovery,
+                |bytes, position| decoder.decode_bytes(bytes, position),
+            )
+            .apply_to(&mut kv_map)?;
+        if let Some(buffer) = pending {
+            apply(&mut kv_map, buffer.clone())?;
         }
+        self.position = position;
+        self.mark_synced();
+        Ok(())
+    }
+
+    // Load the file which replaced the current one, the map is rebuilt from it
+    fn reload_file(&mut self, pending: Option<&Buffer>) -> Result<RecoveryReport> {
+        let mm = IOWriter::map(&self.config, self.config.options().codec())?;
+        let encoder = self
+            .encoder
+            .for_existing_file(&self.config.path, mm.codec())?;
+        let decoder = encoder.decoder();
+        let (mut kv_map, position, report) = mm
+            .iter(self.config.options().recovery, |bytes, position| {
+                decoder.decode_bytes(bytes, position)
+            })
+            .into_map()?;
+        info!(
+            LOG_TAG,
+            "reloaded {} items, content len {}",
+            kv_map.len(),
+            mm.write_offset()
+        );
+        if let Some(buffer) = pending {
+            apply(&mut kv_map, buffer.clone())?;
+        }
+        *self
+            .shared_kv
+            .write()
+            .map_err(|e| Error::LockError(e.to_string()))? = kv_map;
+        self.mm = mm;
+        self.encoder = encoder;
+        self.position = position;
+        self.mark_synced();
+        Ok(report)
+    }
+
+    /// Rewrite the file with the current header if it's in the legacy layout or an older version
+    pub fn upgrade(&mut self) -> Result<()> {
+        if !self.mm.needs_upgrade() {
+            return Ok(());
+        }
+        self.compact("upgraded file")
+    }
+
+    /// Rewrite the file with the loaded items, the lost records left in it are discarded
+    pub fn discard_lost(&mut self) -> Result<()> {
+        self.compact("discarded lost records")
+    }
+
+    /// Rewrite the file with the encoder created by `template` for the new file,
+    /// such as an encryptor with another key, the current encoder is kept if it fails
+    #[cfg(feature = "encryption")]
+    pub fn rewrite_with(&mut self, template: Box<dyn Encoder>) -> Result<()> {
+        let _lock = self.lock(true, None)?;
+        let previous = std::mem::replace(&mut self.encoder, template);
+        let result = self.compact("rewrote file with new encoder");
+        if result.is_err() {
+            self.encoder = previous;
+        }
+        result
+    }
+
+    /**
+    Rewrite the file of `config` which isn't opened by any instance with the encoder created by
+    `template`, such as encrypting a plaintext file. The file is mapped expecting `codec`,
+    and read with the encoder and decoder created by `source` for the mapped file.
+
+    Nothing is changed if any record fails to decode, see [RecoveryStrategy::Fail].
+    */
+    #[cfg(feature = "encryption")]
+    pub fn convert(
+        config: Config,
+        codec: Codec,
+        source: impl FnOnce(&MemoryMap) -> Result<(Box<dyn Encoder>, Box<dyn Decoder>)>,
+        template: Box<dyn Encoder>,
+    ) -> Result<()> {
+        let mm = IOWriter::map(&config, codec)?;
+        let (encoder, decoder) = source(&mm)?;
+        let (kv_map, position, _) = mm
+            .iter(RecoveryStrategy::Fail, |bytes, position| {
+                decoder.decode_bytes(bytes, position)
+            })
+            .into_map()?;
+        let mut writer = IOWriter::new(config, mm, position, new_shared_kv_map(kv_map), encoder);
+        writer.rewrite_with(template)
+    }
+
+    fn compact(&mut self, reason: &str) -> Result<()> {
+        let time_start = Instant::now();
+        let snapshot = self.snapshot()?;
+        self.rewrite_snapshot(&snapshot)?;
+        info!(
+            LOG_TAG,
+            "{} with {} items, cost {:?}",
+            reason,
+            self.position,
+            time_start.elapsed()
+        );
         Ok(())
     }
 
     fn snapshot(&self) -> Result<HashMap<String, Buffer>> {
         self.shared_kv
-            .read()
+            .write()
             .map_err(|e| Error::LockError(e.to_string()))
-            .map(|kv_map| kv_map.clone())
+            .map(|mut kv_map| {
+                // Expired items are invisible to readers, drop them from memory as well
+                kv_map.retain(|_, buffer| !buffer.is_expired());
+                kv_map.clone()
+            })
     }
 
+    // Write the snapshot to a temp file and replace the current file with it,
+    // so a crash in the middle leaves either the old or the new complete file
     fn rewrite_snapshot(&mut self, snapshot: &HashMap<String, Buffer>) -> Result<()> {
-        self.mm.reset();
-        self.position = 0;
-        for buffer in snapshot.values() {
-            let bytes = self.encoder.encode_to_bytes(buffer, self.position)?;
-            self.ensure_capacity(bytes.len())?;
-            self.mm.append(&bytes)?;
-            self.position += 1;
+        let mut temp_config = self.config.create_temp()?;
+        let encoder = self
+            .encoder
+            .for_new_file(&temp_config.path, temp_config.options().checksum)?;
+        let mut mm = IOWriter::map(&temp_config, encoder.codec())?;
+        let mut position = 0;
+        for buffer in snapshot.values().filter(|buffer| !buffer.is_expired()) {
+            let bytes = encoder.encode_to_bytes(buffer, position)?;
+            let required_len = mm.write_offset() + bytes.len();
+            if required_len > mm.len() {
+                temp_config.expand(required_len as u64)?;
+                mm = IOWriter::map(&temp_config, encoder.codec())?;
+            }
+            mm.append(&bytes)?;
+            position += 1;
+        }
+        if temp_config.shrink(mm.write_offset() as u64)? {
+            mm = IOWriter::map(&temp_config, encoder.codec())?;
         }
+        mm.set_sequence(self.mm.sequence().wrapping_add(1));
+        mm.flush()?;
+        self.config.replace(temp_config)?;
+        self.mm = mm;
+        self.position = position;
+        self.encoder = encoder;
+        self.mark_synced();
         Ok(())
     }
 
     fn ensure_capacity(&mut self, incoming_len: usize) -> Result<()> {
-        while self.mm.write_offset() + incoming_len > self.mm.len() {
-            self.expand()?;
+        let required_len = self.mm.write_offset() + incoming_len;
+        if required_len > self.mm.len() {
+            self.config.expand(required_len as u64)?;
+            self.mm = IOWriter::map(&self.config, self.encoder.codec())?;
         }
         Ok(())
     }
 
-    fn expand(&mut self) -> Result<()> {
-        self.config.expand()?;
-        self.mm = MemoryMap::new(&self.config.file, self.config.file_size()? as usize)?;
-        Ok(())
+    /// Map the file of `config`, the header with `codec` is written if it's a new file
+    pub fn map(config: &Config, codec: Codec) -> Result<MemoryMap> {
+        let len = config.file_size()? as usize;
+        let populate = config.options().populate;
+        if config.options().read_only {
+            MemoryMap::read_only(&config.file, len, populate, codec)
+        } else {
+            MemoryMap::new(&config.file, len, populate, codec)
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_encoder(&mut self, encoder: Box<dyn Encoder>) {
+        self.encoder = encoder;
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.mm.flush()
     }
 
     pub fn remove_file(&mut self) -> Result<()> {
+        // Other processes find the file removed once they get the lock
+        let _lock = self.lock(true, None)?;
         self.config.remove_file()
     }
 }
@@ -117,22 +397,24 @@ impl IOWriter {
 #[cfg(test)]
 mod tests {
     use super::IOWriter;
+    use crate::Error::KeyNotFound;
     use crate::core::buffer::Buffer;
     use crate::core::config::Config;
     #[cfg(not(feature = "encryption"))]
     use crate::core::crc::CrcEncoderDecoder;
     #[cfg(feature = "encryption")]
     use crate::core::encrypt::Encryptor;
+    use crate::core::header::Codec;
     use crate::core::memory_map::MemoryMap;
     use crate::core::mmkv_impl::MmkvImpl;
-    use crate::core::shared_state::{new_shared_kv_map, SharedKvMap};
-    use crate::Error::KeyNotFound;
+    use crate::core::shared_state::{SharedKvMap, new_shared_kv_map};
     use std::collections::HashMap;
     use std::fs;
     use std::path::Path;
+    use std::time::Duration;
 
     #[cfg(feature = "encryption")]
-    const TEST_KEY: &str = "88C51C536176AD8A8EE4A06F62EE897E";
+    use crate::core::config::TEST_KEY;
 
     #[cfg(not(feature = "encryption"))]
     fn test_encoder(_file_name: &str) -> Box<dyn crate::core::buffer::Encoder> {
@@ -141,17 +423,12 @@ mod tests {
 
     #[cfg(feature = "encryption")]
     fn test_encoder(file_name: &str) -> Box<dyn crate::core::buffer::Encoder> {
-        let encryptor = Encryptor::init(Path::new(file_name), TEST_KEY);
+        let encryptor = Encryptor::init(Path::new(file_name), &TEST_KEY).unwrap();
         Box::new(encryptor)
     }
 
     fn reopen_mmkv(config: &Config) -> MmkvImpl {
-        MmkvImpl::new(
-            config.try_clone().unwrap(),
-            #[cfg(feature = "encryption")]
-            TEST_KEY,
-        )
-        .unwrap()
+        MmkvImpl::new(config.reopen().unwrap()).unwrap()
     }
 
     fn new_shared_state() -> SharedKvMap {
@@ -175,16 +452,16 @@ mod tests {
         let _ = fs::remove_file(file_name);
         let _ = fs::remove_file(format!("{file_name}.meta"));
         let config = Config::new(Path::new(file_name), 64).unwrap();
-        let mm = MemoryMap::new(&config.file, config.file_size().unwrap() as usize).unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
+        )
+        .unwrap();
         let encoder = test_encoder(file_name);
         let shared_kv = new_shared_state();
-        let mut writer = IOWriter::new(
-            config.try_clone().unwrap(),
-            mm,
-            0,
-            shared_kv.clone(),
-            encoder,
-        );
+        let mut writer = IOWriter::new(config.reopen().unwrap(), mm, 0, shared_kv.clone(), encoder);
 
         let large_value = vec![7u8; 256];
         let buffer = Buffer::new("large", large_value.as_slice());
@@ -192,6 +469,8 @@ mod tests {
         writer.write(buffer, false).unwrap();
 
         assert!(writer.mm.len() >= writer.mm.write_offset());
+        // The file doubles from 64 until the record fits
+        assert_eq!(writer.mm.len(), 512);
         assert_eq!(writer.position, 1);
         assert_eq!(
             shared_kv
@@ -220,16 +499,16 @@ mod tests {
         let _ = fs::remove_file(file_name);
         let _ = fs::remove_file(format!("{file_name}.meta"));
         let config = Config::new(Path::new(file_name), 96).unwrap();
-        let mm = MemoryMap::new(&config.file, config.file_size().unwrap() as usize).unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
+        )
+        .unwrap();
         let encoder = test_encoder(file_name);
         let shared_kv = new_shared_state();
-        let mut writer = IOWriter::new(
-            config.try_clone().unwrap(),
-            mm,
-            0,
-            shared_kv.clone(),
-            encoder,
-        );
+        let mut writer = IOWriter::new(config.reopen().unwrap(), mm, 0, shared_kv.clone(), encoder);
 
         let value1 = vec![1u8; 40];
         let value2 = vec![2u8; 40];
@@ -289,16 +568,16 @@ mod tests {
         let _ = fs::remove_file(file_name);
         let _ = fs::remove_file(format!("{file_name}.meta"));
         let config = Config::new(Path::new(file_name), 96).unwrap();
-        let mm = MemoryMap::new(&config.file, config.file_size().unwrap() as usize).unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
+        )
+        .unwrap();
         let encoder = test_encoder(file_name);
         let shared_kv = new_shared_state();
-        let mut writer = IOWriter::new(
-            config.try_clone().unwrap(),
-            mm,
-            0,
-            shared_kv.clone(),
-            encoder,
-        );
+        let mut writer = IOWriter::new(config.reopen().unwrap(), mm, 0, shared_kv.clone(), encoder);
 
         let value1 = vec![1u8; 40];
         let value2 = vec![2u8; 40];
@@ -333,22 +612,99 @@ mod tests {
         let _ = fs::remove_file(format!("{file_name}.meta"));
     }
 
+    #[test]
+    fn trim_shrinks_sparse_file() {
+        let file_name = "test_writer_trim_shrinks";
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+        let config = Config::new(Path::new(file_name), 64).unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
+        )
+        .unwrap();
+        let encoder = test_encoder(file_name);
+        let shared_kv = new_shared_state();
+        let mut writer = IOWriter::new(config.reopen().unwrap(), mm, 0, shared_kv.clone(), encoder);
+
+        let large = Buffer::new("k1", vec![1u8; 900].as_slice());
+        insert(&shared_kv, large.clone());
+        writer.write(large, false).unwrap();
+        assert_eq!(config.reopen().unwrap().file_size().unwrap(), 1024);
+
+        // Overwrite the large value with small ones until trim, which leaves the file mostly empty
+        for i in 0..10 {
+            let small = Buffer::new("k1", i);
+            insert(&shared_kv, small.clone());
+            writer.write(small, true).unwrap();
+            if writer.position == 1 {
+                break;
+            }
+        }
+        assert_eq!(writer.position, 1);
+        let file_size = config.reopen().unwrap().file_size().unwrap();
+        assert!(file_size <= 128);
+        assert_eq!(writer.mm.len() as u64, file_size);
+
+        let reopened = reopen_mmkv(&config);
+        assert!(reopened.get("k1").unwrap().parse::<i32>().is_ok());
+
+        writer.remove_file().unwrap();
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+    }
+
+    #[test]
+    fn trim_drops_expired_items() {
+        let file_name = "test_writer_trim_drops_expired_items";
+        let _ = fs::remove_file(file_name);
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+        let config = Config::new(Path::new(file_name), 96).unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
+        )
+        .unwrap();
+        let encoder = test_encoder(file_name);
+        let shared_kv = new_shared_state();
+        let mut writer = IOWriter::new(config.reopen().unwrap(), mm, 0, shared_kv.clone(), encoder);
+
+        let buffer1 = Buffer::new("k1", vec![1u8; 40].as_slice()).with_ttl(Duration::ZERO);
+        insert(&shared_kv, buffer1.clone());
+        writer.write(buffer1, false).unwrap();
+        let buffer2 = Buffer::new("k2", vec![2u8; 40].as_slice());
+        insert(&shared_kv, buffer2.clone());
+        writer.write(buffer2, false).unwrap();
+        let buffer3 = Buffer::new("k2", vec![3u8; 120].as_slice());
+        insert(&shared_kv, buffer3.clone());
+        writer.write(buffer3, true).unwrap();
+
+        assert_eq!(writer.position, 1);
+        assert!(!shared_kv.read().unwrap().contains_key("k1"));
+
+        writer.remove_file().unwrap();
+        let _ = fs::remove_file(format!("{file_name}.meta"));
+    }
+
     #[test]
     fn trim_reads_latest_shared_snapshot() {
         let file_name = "test_writer_trim_latest_shared_snapshot";
         let _ = fs::remove_file(file_name);
         let _ = fs::remove_file(format!("{file_name}.meta"));
         let config = Config::new(Path::new(file_name), 96).unwrap();
-        let mm = MemoryMap::new(&config.file, config.file_size().unwrap() as usize).unwrap();
+        let mm = MemoryMap::new(
+            &config.file,
+            config.file_size().unwrap() as usize,
+            true,
+            Codec::legacy(),
+        )
+        .unwrap();
         let encoder = test_encoder(file_name);
         let shared_kv = new_shared_state();
-        let mut writer = IOWriter::new(
-            config.try_clone().unwrap(),
-            mm,
-            0,
-            shared_kv.clone(),
-            encoder,
-        );
+        let mut writer = IOWriter::new(config.reopen().unwrap(), mm, 0, shared_kv.clone(), encoder);
 
         let initial = vec![1u8; 40];
         let mid = vec![2u8; 120];
@@ -324,8 +324,9 @@ macro_rules! impl_get {
     };
 }
 
+/// Open the instance in `dir`, encrypted with the hexadecimal `key` unless it's null
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn new_instance(dir: *const c_char) -> *const c_void {
+pub unsafe extern "C" fn new_instance(dir: *const c_char, key: *const c_char) -> *const c_void {
     let dir_str_result = unsafe {
         // SAFETY: we assume ffi caller passed valid c_char
         CStr::from_ptr(dir)
@@ -338,7 +339,23 @@ pub unsafe extern "C" fn new_instance(dir: *const c_char) -> *const c_void {
             return std::ptr::null();
         }
     };
-    match MMKV::new(dir_str) {
+    let key_str = if key.is_null() {
+        None
+    } else {
+        let key_str_result = unsafe {
+            // SAFETY: we assume ffi caller passed valid c_char
+            CStr::from_ptr(key)
+        }
+        .to_str();
+        match key_str_result {
+            Ok(key_str) => Some(key_str),
+            Err(_) => {
+                error!(LOG_TAG, "Invalid encryption key");
+                return std::ptr::null();
+            }
+        }
+    };
+    match MMKV::builder(dir_str).open_with_key(key_str) {
         Ok(mmkv) => Box::into_raw(Box::new(mmkv)) as *const c_void,
         Err(e) => {
             error!(
@@ -57,7 +57,10 @@ struct RawTypedArray {
   uintptr_t len;
 };
 
-const void *new_instance(const char *dir);
+/**
+ * Open the instance in `dir`, encrypted with the hexadecimal `key` unless it's null
+ */
+const void *new_instance(const char *dir, const char *key);
 
 void set_logger(struct NativeLogger logger);
 
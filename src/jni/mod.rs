@@ -524,18 +524,18 @@ pub unsafe extern "C" fn Java_net_yangkx_mmkv_MMKV_initialize(
     mut env: EnvUnowned,
     _: JClass,
     dir: JString,
-    #[cfg(feature = "encryption")] key: JString,
+    key: JString,
 ) -> jlong {
     let outcome = env
         .with_env_no_catch(|env| -> jni::errors::Result<jlong> {
             let path: String = dir.try_to_string(env)?;
-            #[cfg(feature = "encryption")]
-            let key: String = key.try_to_string(env)?;
-            match MMKV::new(
-                &path,
-                #[cfg(feature = "encryption")]
-                &key,
-            ) {
+            // The store is not encrypted if the key is null
+            let key: Option<String> = if key.is_null() {
+                None
+            } else {
+                Some(key.try_to_string(env)?)
+            };
+            match MMKV::builder(&path).open_with_key(key.as_deref()) {
                 Ok(mmkv) => Ok(Box::into_raw(Box::new(mmkv)) as jlong),
                 Err(e) => {
                     let log_str = format!(
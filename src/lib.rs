@@ -4,19 +4,34 @@
 //! use mmkv::MMKV;
 //!
 //! let temp_dir = std::env::temp_dir();
-//! let mmkv = MMKV::new(temp_dir.to_str().unwrap(), #[cfg(feature = "encryption")] "88C51C536176AD8A8EE4A06F62EE897E").unwrap();
+//! let mmkv = MMKV::new(temp_dir.to_str().unwrap()).unwrap();
 //! mmkv.put("key1", 1).unwrap();
 //! assert_eq!(mmkv.get("key1"), Ok(1));
 //! // Not actually needed unless you intend to delete all data
 //! mmkv.clear_data().unwrap();
 //! ```
 //! For detailed API doc, see [MMKV]
-pub use crate::core::buffer::{FromBytes, ProvideTypeToken, ToBytes, TypeToken};
+pub use crate::builder::MMKVBuilder;
+pub use crate::core::buffer::{
+    FromBytes, Increment, ProvideTypeToken, ToBytes, TypeToken, ValueType,
+};
+#[cfg(feature = "compression")]
+pub use crate::core::compression::Compression;
+pub use crate::core::config::Checksum;
+pub use crate::core::entry::{Entries, Entry};
+pub use crate::core::failure::WriteFailurePolicy;
+pub use crate::core::observer::{Change, Subscription};
+pub use crate::core::recovery::{RecoveryReport, RecoveryStrategy};
+pub use crate::core::transaction::Transaction;
 pub use crate::log::LogLevel;
 pub use crate::log::Logger;
 pub use crate::mmkv::MMKV;
 
-#[derive(Debug, PartialEq)]
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub use crate::core::fuzz;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     KeyNotFound,
     DecodeFailed(String),
@@ -26,6 +41,23 @@ pub enum Error {
     EncodeFailed(String),
     IOError(String),
     LockError(String),
+    InvalidId(String),
+    NumericOverflow,
+    InvalidConfig(String),
+    /// The file is not a MMKV file
+    UnrecognizedFormat(String),
+    /// The file is written by a newer version with the format version
+    UnsupportedVersion(u16),
+    /// The file is encoded differently, e.g. an encrypted file opened without a key
+    CodecMismatch(String),
+    /// The instance is opened by [MMKV::open_read_only], which can't be written
+    ReadOnly,
+    /// The encryption key is malformed, it must be 16 or 32 bytes
+    #[cfg(feature = "encryption")]
+    InvalidKey(String),
+    /// The store is encrypted with another key, nothing is read or written
+    #[cfg(feature = "encryption")]
+    WrongKey,
     #[cfg(feature = "encryption")]
     DecryptFailed(String),
     #[cfg(feature = "encryption")]
@@ -71,9 +103,9 @@ macro_rules! verbose {
     }
 }
 
+mod builder;
 mod core;
 #[cfg(not(target_os = "android"))]
-#[cfg(not(feature = "encryption"))]
 /// Expose the C API
 mod ffi;
 #[cfg(target_os = "android")]
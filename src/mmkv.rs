@@ -1,22 +1,34 @@
 use std::collections::HashMap;
 use std::fs;
+use std::mem::ManuallyDrop;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
 
 use once_cell::sync::Lazy;
 
-use crate::Error::{IOError, LockError};
-use crate::core::buffer::{Buffer, FromBytes, ProvideTypeToken, ToBytes};
-use crate::core::config::Config;
+#[cfg(feature = "encryption")]
+use crate::Error::InvalidConfig;
+use crate::Error::{IOError, InvalidId, KeyNotFound, LockError, NumericOverflow};
+use crate::builder::MMKVBuilder;
+use crate::core::buffer::{Buffer, FromBytes, Increment, ProvideTypeToken, ToBytes};
+#[cfg(feature = "encryption")]
+use crate::core::config::Key;
+use crate::core::config::{Config, Options};
+use crate::core::entry::Entries;
+use crate::core::failure::WriteFailurePolicy;
 use crate::core::mmkv_impl::MmkvImpl;
+use crate::core::observer::{Change, Filter, Subscription};
+use crate::core::recovery::RecoveryReport;
+use crate::core::transaction::Transaction;
 use crate::log::logger;
-use crate::{LogLevel, Result};
+use crate::{Error, LogLevel, Result};
 
 const LOG_TAG: &str = "MMKV:Core";
-const DEFAULT_FILE_NAME: &str = "mini_mmkv";
+pub(crate) const DEFAULT_FILE_NAME: &str = "mini_mmkv";
 
-fn page_size() -> usize {
+pub(crate) fn page_size() -> usize {
     static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
 
     match PAGE_SIZE.load(Ordering::Relaxed) {
@@ -36,22 +48,29 @@ static INSTANCE_MAP: Lazy<RwLock<HashMap<PathBuf, Weak<RwLock<MmkvImpl>>>>> =
 
 pub struct MMKV {
     path: PathBuf,
-    #[cfg(feature = "encryption")]
-    key: String,
-    mmkv_impl: Arc<RwLock<MmkvImpl>>,
+    // Dropped manually while holding the lock of INSTANCE_MAP, see Drop
+    mmkv_impl: ManuallyDrop<Arc<RwLock<MmkvImpl>>>,
 }
 
 impl Drop for MMKV {
     fn drop(&mut self) {
         let mut map = INSTANCE_MAP.write().unwrap();
-        if Arc::strong_count(&self.mmkv_impl) == 1 {
+        // Release the instance inside the lock, otherwise the file may be reopened
+        // before the pending writes of the last instance are finished
+        let mmkv_impl = unsafe { ManuallyDrop::take(&mut self.mmkv_impl) };
+        // Read-only instances are not registered, keep the instance opened at the same path
+        let registered = map
+            .get(&self.path)
+            .is_some_and(|weak| weak.as_ptr() == Arc::as_ptr(&mmkv_impl));
+        if registered && Arc::strong_count(&mmkv_impl) == 1 {
             map.remove(&self.path);
         }
         debug!(
             LOG_TAG,
             "drop MMKV, remain ref count {}",
-            Arc::strong_count(&self.mmkv_impl) - 1
+            Arc::strong_count(&mmkv_impl) - 1
         );
+        drop(mmkv_impl);
     }
 }
 
@@ -63,56 +82,242 @@ impl MMKV {
     Calling [new](MMKV::new) multiple times with same parameter `dir` will get different MMKV
     instances share the same mmap, it's safe to call get or put concurrently on these instances.
 
-    If enabled feature "encryption", additional param `key` is required,
-    the key should be a hexadecimal string of length 16, for example:
+    The records are protected by the default [Checksum](crate::Checksum), with or without
+    feature "encryption", an encrypted store is opened by [MMKVBuilder::encryption_key].
+
+    This is a shortcut of [with_id](MMKV::with_id) with the default id `mini_mmkv`,
+    use [builder](MMKV::builder) to tune the instance.
+    */
+    pub fn new(dir: &str) -> Result<Self> {
+        MMKV::with_id(dir, DEFAULT_FILE_NAME)
+    }
+
+    /**
+    Initialize a named MMKV instance, the data is stored in file `<dir>/<id>`,
+    so one directory can hold multiple independent stores, for example:
+    ```
+    use mmkv::MMKV;
+
+    let temp_dir = std::env::temp_dir();
+    let dir = temp_dir.to_str().unwrap();
+    let settings = MMKV::with_id(dir, "user_settings").unwrap();
+    let flags = MMKV::with_id(dir, "feature_flags").unwrap();
+    settings.put("key", 1).unwrap();
+    assert!(flags.get::<i32>("key").is_err());
+    settings.clear_data().unwrap();
+    flags.clear_data().unwrap();
+    ```
+    The `id` is used as the file name, it must not be empty, `.` or `..`,
+    contain path separators or NUL, or end with `.meta` or `.tmp`,
+    otherwise [InvalidId](crate::Error::InvalidId) is returned.
+
+    Instances with the same `dir` and `id` share the same mmap, see [new](MMKV::new).
+    */
+    pub fn with_id(dir: &str, id: &str) -> Result<Self> {
+        MMKV::open(dir, id, Options::new(page_size() as u64))
+    }
+
+    /**
+    Initialize a named MMKV instance encrypted with the raw `key`, which is 16 bytes
+    for AES-128 or 32 bytes for AES-256, otherwise [InvalidKey](crate::Error::InvalidKey)
+    is returned, for example:
+    ```
+    use mmkv::MMKV;
+
+    let temp_dir = std::env::temp_dir();
+    let dir = temp_dir.to_str().unwrap();
+    let key = [0x5Au8; 32];
+    let mmkv = MMKV::with_raw_key(dir, "raw_key_doc", &key).unwrap();
+    mmkv.put("key", 1).unwrap();
+    drop(mmkv);
+    let mmkv = MMKV::with_raw_key(dir, "raw_key_doc", &key).unwrap();
+    assert_eq!(mmkv.get("key"), Ok(1));
+    mmkv.clear_data().unwrap();
+    ```
+    The store is the same as the one opened by [MMKVBuilder::encryption_key] with the key
+    in hexadecimal, see [MMKVBuilder::raw_encryption_key] to tune the instance.
+    */
+    #[cfg(feature = "encryption")]
+    pub fn with_raw_key(dir: &str, id: &str, key: &[u8]) -> Result<Self> {
+        let options = Options {
+            key: Some(Key::from_bytes(key)?),
+            ..Options::new(page_size() as u64)
+        };
+        MMKV::open(dir, id, options)
+    }
+
+    /**
+    Create a [MMKVBuilder] to configure the file name, file sizes and other options
+    of the instance stored in `dir`, see [MMKVBuilder] for details.
+    */
+    pub fn builder(dir: &str) -> MMKVBuilder {
+        MMKVBuilder::new(dir)
+    }
+
+    /**
+    Open an existing data file at `path` without writing it, for example a pre-populated store
+    shipped inside a read-only app bundle:
+    ```
+    use mmkv::{Error, MMKV};
+
+    let temp_dir = std::env::temp_dir();
+    let dir = temp_dir.to_str().unwrap();
+    let mmkv = MMKV::with_id(dir, "read_only_doc").unwrap();
+    mmkv.put_sync("key", 1).unwrap();
+    let path = temp_dir.join("read_only_doc");
+    let bundled = MMKV::open_read_only(path.to_str().unwrap()).unwrap();
+    assert_eq!(bundled.get("key"), Ok(1));
+    assert_eq!(bundled.put("key", 2), Err(Error::ReadOnly));
+    mmkv.clear_data().unwrap();
+    ```
+    The file is mapped `PROT_READ` and is never created, resized or repaired, the writes such as
+    [put](MMKV::put), [delete](MMKV::delete) and [clear_data](MMKV::clear_data) return
+    [ReadOnly](crate::Error::ReadOnly). An encrypted store is opened read-only by
+    [MMKVBuilder::read_only] with its key, the `.meta` file next to it is required as well.
 
-    `88C51C536176AD8A8EE4A06F62EE897E`
+    The instance doesn't share the mmap with the instances opened by [new](MMKV::new),
+    call [reload](MMKV::reload) to load the changes written by them.
     */
-    pub fn new(dir: &str, #[cfg(feature = "encryption")] key: &str) -> Result<Self> {
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        MMKV::open_read_only_with(Path::new(path), Options::new(page_size() as u64))
+    }
+
+    pub(crate) fn open_read_only_with(path: &Path, options: Options) -> Result<Self> {
+        let file_path = fs::canonicalize(path).map_err(|e| {
+            IOError(format!(
+                "failed to canonicalize path {}: {e}",
+                path.display()
+            ))
+        })?;
+        let options = Options {
+            read_only: true,
+            ..options
+        };
+        let config = Config::with_options(file_path.as_path(), options)?;
+        let mmkv_impl = MmkvImpl::new(config)?;
+        Ok(MMKV {
+            path: file_path,
+            mmkv_impl: ManuallyDrop::new(Arc::new(RwLock::new(mmkv_impl))),
+        })
+    }
+
+    pub(crate) fn open(dir: &str, id: &str, options: Options) -> Result<Self> {
+        MMKV::check_id(id)?;
         let dir = MMKV::resolve_dir_path(dir)?;
+        let file_path = dir.join(id);
         let instance_map = INSTANCE_MAP.read().unwrap();
-        if let Some(mmkv) = instance_map.get(&dir).and_then(|mmkv| mmkv.upgrade()) {
+        if let Some(mmkv) = instance_map.get(&file_path).and_then(|mmkv| mmkv.upgrade()) {
             debug!(LOG_TAG, "new MMKV from existing instance");
-            return Ok(MMKV {
-                path: dir.clone(),
-                #[cfg(feature = "encryption")]
-                key: key.to_string(),
-                mmkv_impl: mmkv,
-            });
+            return MMKV::share(file_path, mmkv, &options);
         }
         drop(instance_map);
 
         let mut instance_map = INSTANCE_MAP.write().unwrap();
         // Double check if other thread completed init
-        if let Some(mmkv) = instance_map.get(&dir).and_then(|mmkv| mmkv.upgrade()) {
+        if let Some(mmkv) = instance_map.get(&file_path).and_then(|mmkv| mmkv.upgrade()) {
             debug!(
                 LOG_TAG,
                 "new MMKV from existing instance after double check"
             );
-            return Ok(MMKV {
-                path: dir.clone(),
-                #[cfg(feature = "encryption")]
-                key: key.to_string(),
-                mmkv_impl: mmkv.clone(),
-            });
+            return MMKV::share(file_path, mmkv, &options);
         }
         // Init a new instance
-        let file_path = MMKV::resolve_file_path(&dir);
-        let config = Config::new(file_path.as_path(), page_size() as u64)?;
-        let mmkv_impl = Arc::new(RwLock::new(MmkvImpl::new(
-            config,
-            #[cfg(feature = "encryption")]
-            key,
-        )?));
-        instance_map.insert(dir.clone(), Arc::downgrade(&mmkv_impl));
+        let config = Config::with_options(file_path.as_path(), options)?;
+        let mmkv_impl = Arc::new(RwLock::new(MmkvImpl::new(config)?));
+        instance_map.insert(file_path.clone(), Arc::downgrade(&mmkv_impl));
         Ok(MMKV {
-            path: dir,
-            #[cfg(feature = "encryption")]
-            key: key.to_string(),
-            mmkv_impl,
+            path: file_path,
+            mmkv_impl: ManuallyDrop::new(mmkv_impl),
         })
     }
 
+    // A new handle of the opened instance, which only accepts its current key
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn share(path: PathBuf, mmkv_impl: Arc<RwLock<MmkvImpl>>, options: &Options) -> Result<Self> {
+        #[cfg(feature = "encryption")]
+        mmkv_impl
+            .read()
+            .map_err(|e| LockError(e.to_string()))?
+            .check_key(options.key.as_ref())?;
+        Ok(MMKV {
+            path,
+            mmkv_impl: ManuallyDrop::new(mmkv_impl),
+        })
+    }
+
+    /**
+    Encrypt the existing plaintext store `<dir>/<id>` with the hexadecimal `key`, see [new](MMKV::new),
+    so the encryption can be rolled out to the stores created without it.
+
+    The records are rewritten to a temp file which replaces the store atomically,
+    nothing is changed if any record fails the integrity check.
+    The store must not be opened by any instance, in this process or others.
+    */
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_in_place(dir: &str, id: &str, key: &str) -> Result<()> {
+        let key = Key::from_hex(key)?;
+        MMKV::convert_closed(dir, id, |config| MmkvImpl::encrypt_file(config, &key))
+    }
+
+    /**
+    Decrypt the existing store `<dir>/<id>` encrypted with the hexadecimal `key`,
+    the records are protected by [Checksum::default](crate::Checksum) afterwards,
+    see [encrypt_in_place](MMKV::encrypt_in_place).
+
+    The decrypted store can only be opened without a key, see [MMKVBuilder::encryption_key].
+    */
+    #[cfg(feature = "encryption")]
+    pub fn decrypt_in_place(dir: &str, id: &str, key: &str) -> Result<()> {
+        let key = Key::from_hex(key)?;
+        MMKV::convert_closed(dir, id, |config| MmkvImpl::decrypt_file(config, &key))
+    }
+
+    // Convert the existing store `<dir>/<id>` which is not opened in this process
+    #[cfg(feature = "encryption")]
+    fn convert_closed(
+        dir: &str,
+        id: &str,
+        convert: impl FnOnce(Config) -> Result<()>,
+    ) -> Result<()> {
+        MMKV::check_id(id)?;
+        let file_path = MMKV::resolve_dir_path(dir)?.join(id);
+        if !file_path.is_file() {
+            return Err(IOError(format!("{} doesn't exist", file_path.display())));
+        }
+        // The store can't be opened in this process until it's converted
+        let instance_map = INSTANCE_MAP.read().unwrap();
+        if instance_map
+            .get(&file_path)
+            .is_some_and(|mmkv| mmkv.strong_count() > 0)
+        {
+            return Err(InvalidConfig(format!(
+                "{} is opened, drop its instances first",
+                file_path.display()
+            )));
+        }
+        convert(Config::with_options(
+            file_path.as_path(),
+            Options::new(page_size() as u64),
+        )?)
+    }
+
+    pub(crate) fn check_id(id: &str) -> Result<()> {
+        if id.is_empty() || id == "." || id == ".." {
+            return Err(InvalidId(format!("'{id}' is not a valid file name")));
+        }
+        if id.contains(['/', '\\', '\0']) {
+            return Err(InvalidId(format!(
+                "'{id}' should not contain path separator or NUL"
+            )));
+        }
+        if id.ends_with(".meta") || id.ends_with(".tmp") {
+            return Err(InvalidId(format!(
+                "'{id}' should not end with '.meta' or '.tmp', which are reserved"
+            )));
+        }
+        Ok(())
+    }
+
     fn resolve_dir_path(dir: &str) -> Result<PathBuf> {
         let path = Path::new(dir);
         if !path.is_dir() {
@@ -129,10 +334,6 @@ impl MMKV {
         Ok(canonical_dir)
     }
 
-    fn resolve_file_path(dir: &Path) -> PathBuf {
-        dir.join(DEFAULT_FILE_NAME)
-    }
-
     /**
     Types must implement [ProvideTypeToken] and [ToBytes] to be persisted in MMKV.
 
@@ -176,14 +377,15 @@ impl MMKV {
     }
 
     let temp_dir = std::env::temp_dir();
-    let mmkv = MMKV::new(temp_dir.to_str().unwrap(), #[cfg(feature = "encryption")] "88C51C536176AD8A8EE4A06F62EE897E").unwrap();
+    let mmkv = MMKV::new(temp_dir.to_str().unwrap()).unwrap();
     let my_struct = MyStruct {
         int_value: 1,
         str_value: "abc".to_string(),
     };
     mmkv.put("my_struct", &my_struct).unwrap();
     let copy: MyStruct = mmkv.get("my_struct").unwrap();
-    assert_eq!(my_struct, copy)
+    assert_eq!(my_struct, copy);
+    mmkv.clear_data().unwrap();
     ```
     */
     pub fn put<T: ProvideTypeToken + ToBytes>(&self, key: &str, value: T) -> Result<()> {
@@ -193,6 +395,126 @@ impl MMKV {
         }
     }
 
+    /**
+    Same as [put](MMKV::put), but wait until the value is written and flushed to file,
+    the result is the real write result instead of the result of posting the write task,
+    use it for critical values which must be durable once this call returns.
+    */
+    pub fn put_sync<T: ProvideTypeToken + ToBytes>(&self, key: &str, value: T) -> Result<()> {
+        let completion = match self.mmkv_impl.write() {
+            Ok(mut mmkv) => mmkv.put_sync(key, Buffer::new(key, value))?,
+            Err(e) => return Err(LockError(e.to_string())),
+        };
+        // Wait without holding the lock, so other threads are not blocked by the IO
+        completion.wait()
+    }
+
+    /**
+    Wait for all pending writes to finish, and flush the data to file synchronously.
+
+    [put](MMKV::put) and [delete](MMKV::delete) return once the change is visible in memory,
+    the data is written to file in background, call this to make sure previous changes are durable.
+    */
+    pub fn flush(&self) -> Result<()> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.flush(),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /**
+    The last failure of the background writes, it's kept until
+    [clear_write_error](MMKV::clear_write_error) called.
+
+    Writes are done in background, so a failure (e.g. disk full) can't be returned by
+    [put](MMKV::put), it's recorded here and returned by following [flush](MMKV::flush),
+    see [set_write_failure_policy](MMKV::set_write_failure_policy) for how the failed change is handled.
+    */
+    pub fn last_write_error(&self) -> Result<Option<Error>> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => Ok(mmkv.failures().last_error()),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Clear the failure recorded in [last_write_error](MMKV::last_write_error),
+    /// which also makes the instance writable again under [WriteFailurePolicy::ReadOnly].
+    pub fn clear_write_error(&self) -> Result<()> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.failures().clear(),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Set how to deal with a failed background write, the default is
+    /// [WriteFailurePolicy::KeepInMemory].
+    pub fn set_write_failure_policy(&self, policy: WriteFailurePolicy) -> Result<()> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.failures().set_policy(policy),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Set a callback which is called when a background write failed, it replaces the previous one,
+    /// the callback is called in the same thread with [on_change](MMKV::on_change).
+    pub fn set_write_error_callback<F>(&self, callback: F) -> Result<()>
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.failures().set_callback(Some(Arc::new(callback))),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Remove the callback set by [set_write_error_callback](MMKV::set_write_error_callback).
+    pub fn remove_write_error_callback(&self) -> Result<()> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.failures().set_callback(None),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /**
+    Same as [put](MMKV::put), but the value expires after `ttl`,
+    expired values are invisible to readers, and dropped from the file on next trim.
+    ```
+    use mmkv::MMKV;
+    use std::time::Duration;
+
+    let temp_dir = std::env::temp_dir();
+    let mmkv = MMKV::with_id(temp_dir.to_str().unwrap(), "ttl_doc").unwrap();
+    mmkv.put_with_ttl("token", "abc", Duration::from_millis(10)).unwrap();
+    assert_eq!(mmkv.get("token"), Ok("abc".to_string()));
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(mmkv.get::<String>("token").is_err());
+    mmkv.clear_data().unwrap();
+    ```
+    */
+    pub fn put_with_ttl<T: ProvideTypeToken + ToBytes>(
+        &self,
+        key: &str,
+        value: T,
+        ttl: Duration,
+    ) -> Result<()> {
+        match self.mmkv_impl.write() {
+            Ok(mut mmkv) => mmkv.put(key, Buffer::new(key, value).with_ttl(ttl)),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Set the default ttl of this instance, which applies to all values written afterwards
+    /// without an explicit ttl, `None` means never expire. See [put_with_ttl](MMKV::put_with_ttl)
+    pub fn set_default_ttl(&self, ttl: Option<Duration>) -> Result<()> {
+        match self.mmkv_impl.write() {
+            Ok(mut mmkv) => {
+                mmkv.set_default_ttl(ttl);
+                Ok(())
+            }
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
     /// See [MMKV::put]
     pub fn get<T: ProvideTypeToken + FromBytes>(&self, key: &str) -> Result<T> {
         match self.mmkv_impl.read() {
@@ -201,6 +523,92 @@ impl MMKV {
         }
     }
 
+    /**
+    Atomically update the value of `key`, the closure receives the current value
+    (`None` if not exists) and returns the new value, returning `None` deletes the key.
+
+    The whole read-modify-write runs under the write lock of the instance, so it's safe to
+    update the same key concurrently from instances of the same file.
+    The result is the new value, if the current value has a different type,
+    [TypeMissMatch](crate::Error::TypeMissMatch) is returned and nothing changed.
+    ```
+    use mmkv::MMKV;
+
+    let temp_dir = std::env::temp_dir();
+    let mmkv = MMKV::with_id(temp_dir.to_str().unwrap(), "update_doc").unwrap();
+    let value = mmkv.update("name", |name: Option<String>| {
+        Some(name.unwrap_or_default() + "a")
+    });
+    assert_eq!(value, Ok(Some("a".to_string())));
+    assert_eq!(mmkv.increment("count", 2i64), Ok(2));
+    assert_eq!(mmkv.compare_and_swap("count", 2i64, 10), Ok(true));
+    assert_eq!(mmkv.compare_and_swap("count", 2i64, 20), Ok(false));
+    assert_eq!(mmkv.get("count"), Ok(10i64));
+    mmkv.clear_data().unwrap();
+    ```
+    */
+    pub fn update<T, F>(&self, key: &str, f: F) -> Result<Option<T>>
+    where
+        T: ProvideTypeToken + ToBytes + FromBytes,
+        F: FnOnce(Option<T>) -> Option<T>,
+    {
+        let mut mmkv = self
+            .mmkv_impl
+            .write()
+            .map_err(|e| LockError(e.to_string()))?;
+        let current = match mmkv.get(key).and_then(|buffer| buffer.parse::<T>()) {
+            Ok(value) => Some(value),
+            Err(KeyNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let existed = current.is_some();
+        match f(current) {
+            Some(value) => {
+                mmkv.put(key, Buffer::new(key, &value))?;
+                Ok(Some(value))
+            }
+            None => {
+                if existed {
+                    mmkv.delete(key)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Atomically add `delta` to the value of `key` and return the new value,
+    /// a missing key is treated as zero. See [update](MMKV::update)
+    pub fn increment<T: Increment + Default>(&self, key: &str, delta: T) -> Result<T> {
+        let mut overflowed = false;
+        let value = self.update(key, |current: Option<T>| {
+            let current = current.unwrap_or_default();
+            let value = current.checked_increment(delta);
+            overflowed = value.is_none();
+            Some(value.unwrap_or(current))
+        })?;
+        if overflowed {
+            return Err(NumericOverflow);
+        }
+        Ok(value.unwrap_or_default())
+    }
+
+    /// Atomically replace the value of `key` with `new` if the current value equals to `expected`,
+    /// returns whether the value was replaced. See [update](MMKV::update)
+    pub fn compare_and_swap<T>(&self, key: &str, expected: T, new: T) -> Result<bool>
+    where
+        T: ProvideTypeToken + ToBytes + FromBytes + PartialEq,
+    {
+        let mut swapped = false;
+        self.update(key, |current: Option<T>| match current {
+            Some(current) if current == expected => {
+                swapped = true;
+                Some(new)
+            }
+            current => current,
+        })?;
+        Ok(swapped)
+    }
+
     pub fn delete(&self, key: &str) -> Result<()> {
         match self.mmkv_impl.write() {
             Ok(mut mmkv) => mmkv.delete(key),
@@ -208,6 +616,188 @@ impl MMKV {
         }
     }
 
+    /**
+    Apply a group of changes atomically, the changes become visible to readers at once,
+    and are persisted as one record, so after a crash either all or none of them are restored.
+    ```
+    use mmkv::MMKV;
+
+    let temp_dir = std::env::temp_dir();
+    let mmkv = MMKV::with_id(temp_dir.to_str().unwrap(), "batch_doc").unwrap();
+    mmkv.put("stale", true).unwrap();
+    mmkv.batch(|tx| {
+        tx.put("user_id", 1024);
+        tx.put("user_name", "name");
+        tx.delete("stale");
+    }).unwrap();
+    assert_eq!(mmkv.get("user_id"), Ok(1024));
+    assert!(mmkv.get::<bool>("stale").is_err());
+    mmkv.clear_data().unwrap();
+    ```
+    */
+    pub fn batch<F: FnOnce(&mut Transaction)>(&self, f: F) -> Result<()> {
+        let mut transaction = Transaction::default();
+        f(&mut transaction);
+        match self.mmkv_impl.write() {
+            Ok(mut mmkv) => mmkv.batch(transaction.into_changes()),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /**
+    Register a callback which is called after any key of this instance changed,
+    including [put](MMKV::put), [delete](MMKV::delete), [batch](MMKV::batch)
+    and [clear_data](MMKV::clear_data).
+
+    The callbacks are called in a dedicated thread in the order of the changes,
+    never under the lock of the instance, so it's safe to access MMKV in the callback.
+    A change reverted because its write failed is followed by the change restoring the value.
+    The callback is unregistered when the returned [Subscription] dropped.
+    ```
+    use mmkv::MMKV;
+    use std::sync::mpsc::channel;
+
+    let temp_dir = std::env::temp_dir();
+    let mmkv = MMKV::with_id(temp_dir.to_str().unwrap(), "observe_doc").unwrap();
+    let (sender, receiver) = channel();
+    let subscription = mmkv.observe("key", move |change| {
+        let new_value = change.new_value().map(|entry| entry.parse::<i32>().unwrap());
+        sender.send(new_value).unwrap();
+    }).unwrap();
+    mmkv.put("key", 1).unwrap();
+    assert_eq!(receiver.recv(), Ok(Some(1)));
+    drop(subscription);
+    mmkv.clear_data().unwrap();
+    ```
+    */
+    pub fn on_change<F>(&self, callback: F) -> Result<Subscription>
+    where
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        self.subscribe(Filter::All, callback)
+    }
+
+    /// Same as [on_change](MMKV::on_change), but only for the `key`.
+    pub fn observe<F>(&self, key: &str, callback: F) -> Result<Subscription>
+    where
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        self.subscribe(Filter::Key(key.to_string()), callback)
+    }
+
+    /// Same as [on_change](MMKV::on_change), but only for keys start with the `prefix`.
+    pub fn observe_prefix<F>(&self, prefix: &str, callback: F) -> Result<Subscription>
+    where
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        self.subscribe(Filter::Prefix(prefix.to_string()), callback)
+    }
+
+    fn subscribe<F>(&self, filter: Filter, callback: F) -> Result<Subscription>
+    where
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.observers().subscribe(filter, callback),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Returns `true` if the instance contains a value for the `key`, regardless of its type.
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.contains_key(key),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// All keys of the instance, in arbitrary order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.keys(),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// The number of key-value pairs in the instance.
+    pub fn count(&self) -> Result<usize> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.count(),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Returns `true` if the instance contains no key-value pairs.
+    pub fn is_empty(&self) -> Result<bool> {
+        self.count().map(|count| count == 0)
+    }
+
+    /// What was lost when the data file was last loaded, by opening or [reload](MMKV::reload),
+    /// see [RecoveryStrategy](crate::RecoveryStrategy).
+    pub fn recovery_report(&self) -> Result<RecoveryReport> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => Ok(mmkv.recovery_report()),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /// Load the data file again, for example after it's replaced by a backup,
+    /// the writes before it are finished first.
+    /// See [MMKVBuilder::detect_changes](crate::MMKVBuilder::detect_changes) to reload automatically.
+    pub fn reload(&self) -> Result<()> {
+        match self.mmkv_impl.write() {
+            Ok(mut mmkv) => mmkv.reload(),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /**
+    Encrypt the data file with the new hexadecimal `key`, see [new](MMKV::new), for example
+    if the current key is suspected to be leaked.
+
+    The snapshot of the data is rewritten under the new key with a new nonce after the pending
+    writes finished, and replaces the file atomically, so a crash leaves the file encrypted
+    with either key. Afterwards the instance must be opened with the new key,
+    opening it with the old key fails while it's still opened in this process.
+
+    It's not supported in multi-process mode, [InvalidConfig](crate::Error::InvalidConfig)
+    is returned.
+    */
+    #[cfg(feature = "encryption")]
+    pub fn rekey(&self, key: &str) -> Result<()> {
+        let key = Key::from_hex(key)?;
+        match self.mmkv_impl.write() {
+            Ok(mut mmkv) => mmkv.rekey(key),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
+    /**
+    Iterate over a snapshot of all entries, in arbitrary order.
+    ```
+    use mmkv::{MMKV, ValueType};
+
+    let temp_dir = std::env::temp_dir();
+    let mmkv = MMKV::with_id(temp_dir.to_str().unwrap(), "iter_doc").unwrap();
+    mmkv.put("key1", 1).unwrap();
+    mmkv.put("key2", "value").unwrap();
+    for (key, entry) in mmkv.iter().unwrap() {
+        match entry.value_type() {
+            ValueType::I32 => assert_eq!(entry.parse::<i32>(), Ok(1)),
+            ValueType::Str => assert_eq!(entry.parse::<String>(), Ok("value".to_string())),
+            _ => unreachable!("unexpected entry {key}"),
+        }
+    }
+    mmkv.clear_data().unwrap();
+    ```
+    */
+    pub fn iter(&self) -> Result<Entries> {
+        match self.mmkv_impl.read() {
+            Ok(mmkv) => mmkv.snapshot().map(Entries::new),
+            Err(e) => Err(LockError(e.to_string())),
+        }
+    }
+
     /**
     Clear all data.
     */
@@ -217,13 +807,9 @@ impl MMKV {
             .write()
             .map_err(|e| LockError(e.to_string()))?;
         mmkv_impl.clear_data()?;
-        let file_path = MMKV::resolve_file_path(&self.path);
-        let config = Config::new(file_path.as_path(), page_size() as u64)?;
-        *mmkv_impl = MmkvImpl::new(
-            config,
-            #[cfg(feature = "encryption")]
-            &self.key,
-        )?;
+        let config = Config::with_options(self.path.as_path(), mmkv_impl.options().clone())?;
+        let new_impl = mmkv_impl.reopen(config)?;
+        *mmkv_impl = new_impl;
         Ok(())
     }
 
@@ -287,7 +873,7 @@ mod tests {
     use std::sync::Arc;
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    use crate::Error::KeyNotFound;
+    use crate::Error::TypeMissMatch;
 
     use super::*;
 
@@ -296,19 +882,9 @@ mod tests {
     fn test_instance() {
         let _ = fs::remove_file("mini_mmkv");
         let _ = fs::remove_file("mini_mmkv.meta");
-        let mut mmkv = MMKV::new(
-            ".",
-            #[cfg(feature = "encryption")]
-            "88C51C536176AD8A8EE4A06F62EE897E",
-        )
-        .unwrap();
+        let mut mmkv = MMKV::new(".").unwrap();
         debug!(LOG_TAG, "---------------");
-        mmkv = MMKV::new(
-            ".",
-            #[cfg(feature = "encryption")]
-            "88C51C536176AD8A8EE4A06F62EE897E",
-        )
-        .unwrap();
+        mmkv = MMKV::new(".").unwrap();
         mmkv.put("first", 1i32).unwrap();
         mmkv.put("second", 2i32).unwrap();
         assert_eq!(mmkv.get("first"), Ok(1));
@@ -358,12 +934,7 @@ mod tests {
         drop(mmkv);
         debug!(LOG_TAG, "---------------");
 
-        mmkv = MMKV::new(
-            ".",
-            #[cfg(feature = "encryption")]
-            "88C51C536176AD8A8EE4A06F62EE897E",
-        )
-        .unwrap();
+        mmkv = MMKV::new(".").unwrap();
         assert_eq!(mmkv.get("first"), Ok("one".to_string()));
         assert_eq!(mmkv.get::<i32>("second"), Err(KeyNotFound));
         mmkv.clear_data().unwrap();
@@ -381,18 +952,8 @@ mod tests {
         fs::create_dir_all(&dir).unwrap();
 
         let dir_with_trailing_slash = format!("{}/", dir.display());
-        let mmkv = MMKV::new(
-            dir.to_str().unwrap(),
-            #[cfg(feature = "encryption")]
-            "88C51C536176AD8A8EE4A06F62EE897E",
-        )
-        .unwrap();
-        let mmkv_same_dir = MMKV::new(
-            &dir_with_trailing_slash,
-            #[cfg(feature = "encryption")]
-            "88C51C536176AD8A8EE4A06F62EE897E",
-        )
-        .unwrap();
+        let mmkv = MMKV::new(dir.to_str().unwrap()).unwrap();
+        let mmkv_same_dir = MMKV::new(&dir_with_trailing_slash).unwrap();
 
         assert!(Arc::ptr_eq(&mmkv.mmkv_impl, &mmkv_same_dir.mmkv_impl));
 
@@ -401,4 +962,186 @@ mod tests {
         drop(mmkv);
         let _ = fs::remove_dir_all(&dir);
     }
+
+    #[test]
+    fn test_concurrent_increment() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("mmkv_increment_{unique}"));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let new_mmkv = || MMKV::new(dir_str).unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mmkv = new_mmkv();
+                    for _ in 0..100 {
+                        mmkv.increment("counter", 1i64).unwrap();
+                    }
+                });
+            }
+        });
+        let mmkv = new_mmkv();
+        assert_eq!(mmkv.get("counter"), Ok(400i64));
+
+        mmkv.put("max", i32::MAX).unwrap();
+        assert_eq!(mmkv.increment("max", 1), Err(NumericOverflow));
+        assert_eq!(mmkv.get("max"), Ok(i32::MAX));
+        assert_eq!(mmkv.increment("max", 1i64), Err(TypeMissMatch));
+        assert_eq!(mmkv.update("max", |_: Option<i32>| None), Ok(None));
+        assert_eq!(mmkv.get::<i32>("max"), Err(KeyNotFound));
+
+        mmkv.clear_data().unwrap();
+        drop(mmkv);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_named_instances() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("mmkv_named_{unique}"));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+
+        let settings = MMKV::with_id(dir_str, "user_settings").unwrap();
+        let flags = MMKV::with_id(dir_str, "feature_flags").unwrap();
+        let settings_again = MMKV::with_id(dir_str, "user_settings").unwrap();
+        assert!(!Arc::ptr_eq(&settings.mmkv_impl, &flags.mmkv_impl));
+        assert!(Arc::ptr_eq(&settings.mmkv_impl, &settings_again.mmkv_impl));
+
+        settings.put("key", 1).unwrap();
+        flags.put("key", true).unwrap();
+        assert_eq!(settings_again.get("key"), Ok(1));
+        assert_eq!(flags.get("key"), Ok(true));
+        assert!(dir.join("user_settings").exists());
+        assert!(dir.join("feature_flags").exists());
+
+        for id in ["", ".", "..", "a/b", "a\\b", "cache.meta", "cache.tmp"] {
+            assert!(matches!(MMKV::with_id(dir_str, id,), Err(InvalidId(_))));
+        }
+
+        settings.clear_data().unwrap();
+        flags.clear_data().unwrap();
+        drop(settings_again);
+        drop(settings);
+        drop(flags);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_rekey() {
+        use crate::Error::{InvalidConfig, WrongKey};
+
+        const OLD_KEY: &str = "88C51C536176AD8A8EE4A06F62EE897E";
+        const NEW_KEY: &str = "0F1E2D3C4B5A69788796A5B4C3D2E1F0";
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("mmkv_rekey_{unique}"));
+        fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let meta_path = dir.join(format!("{DEFAULT_FILE_NAME}.meta"));
+        let open = |key: &str| MMKV::builder(dir_str).encryption_key(key).open();
+
+        let mmkv = open(OLD_KEY).unwrap();
+        mmkv.put("int", 1).unwrap();
+        mmkv.put("str", "value").unwrap();
+        let old_nonce = fs::read(&meta_path).unwrap();
+        mmkv.rekey(NEW_KEY).unwrap();
+        assert_ne!(fs::read(&meta_path).unwrap(), old_nonce);
+        assert_eq!(mmkv.get("int"), Ok(1));
+        mmkv.put("int", 2).unwrap();
+        assert!(matches!(open(OLD_KEY), Err(WrongKey)));
+        let shared = open(&NEW_KEY.to_lowercase()).unwrap();
+        assert!(Arc::ptr_eq(&mmkv.mmkv_impl, &shared.mmkv_impl));
+        drop(shared);
+        drop(mmkv);
+
+        assert!(matches!(open(OLD_KEY), Err(WrongKey)));
+        let mmkv = open(NEW_KEY).unwrap();
+        assert!(mmkv.recovery_report().unwrap().is_clean());
+        assert_eq!(mmkv.get("int"), Ok(2));
+        assert_eq!(mmkv.get("str"), Ok("value".to_string()));
+        // The opened store can't be converted
+        assert!(matches!(
+            MMKV::decrypt_in_place(dir_str, DEFAULT_FILE_NAME, NEW_KEY),
+            Err(InvalidConfig(_))
+        ));
+        assert!(matches!(
+            MMKV::encrypt_in_place(dir_str, "missing", NEW_KEY),
+            Err(IOError(_))
+        ));
+        // The instance is opened again with the new key
+        mmkv.clear_data().unwrap();
+        mmkv.put("int", 3).unwrap();
+        assert_eq!(mmkv.get("int"), Ok(3));
+        mmkv.clear_data().unwrap();
+        drop(mmkv);
+
+        let shared = MMKV::builder(dir_str)
+            .file_name("multi_process")
+            .multi_process(true)
+            .encryption_key(OLD_KEY)
+            .open()
+            .unwrap();
+        assert!(matches!(shared.rekey(NEW_KEY), Err(InvalidConfig(_))));
+        shared.clear_data().unwrap();
+        drop(shared);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_only() {
+        use crate::Error::ReadOnly;
+
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("mmkv_read_only_{unique}"));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join(DEFAULT_FILE_NAME);
+        let path = file_path.to_str().unwrap();
+        assert!(matches!(MMKV::open_read_only(path), Err(IOError(_))));
+        assert!(!file_path.exists());
+
+        let mmkv = MMKV::new(dir.to_str().unwrap()).unwrap();
+        mmkv.put("int", 1).unwrap();
+        mmkv.put_sync("str", "value").unwrap();
+        let mut permissions = fs::metadata(&file_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&file_path, permissions.clone()).unwrap();
+        let content = fs::read(&file_path).unwrap();
+
+        let read_only = MMKV::open_read_only(path).unwrap();
+        assert!(!Arc::ptr_eq(&mmkv.mmkv_impl, &read_only.mmkv_impl));
+        assert_eq!(read_only.get("int"), Ok(1));
+        assert_eq!(read_only.get("str"), Ok("value".to_string()));
+        assert_eq!(read_only.iter().unwrap().count(), 2);
+        assert_eq!(read_only.put("int", 2), Err(ReadOnly));
+        assert_eq!(read_only.delete("int"), Err(ReadOnly));
+        assert_eq!(read_only.clear_data(), Err(ReadOnly));
+        assert_eq!(read_only.get("int"), Ok(1));
+        drop(read_only);
+        assert_eq!(fs::read(&file_path).unwrap(), content);
+
+        // Dropping the read-only instance keeps the writable one shared
+        let mmkv_again = MMKV::new(dir.to_str().unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&mmkv.mmkv_impl, &mmkv_again.mmkv_impl));
+
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&file_path, permissions).unwrap();
+        mmkv.clear_data().unwrap();
+        drop(mmkv_again);
+        drop(mmkv);
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -1,6 +1,14 @@
 use mmkv::Error::KeyNotFound;
 use mmkv::MMKV;
-use std::fs;
+use std::process::Command;
+use std::{env, fs};
+
+fn open_default() -> MMKV {
+    let builder = MMKV::builder(".");
+    #[cfg(feature = "encryption")]
+    let builder = builder.encryption_key("88C51C536176AD8A8EE4A06F62EE897E");
+    builder.open().unwrap()
+}
 
 #[test]
 fn integration_test() {
@@ -8,12 +16,7 @@ fn integration_test() {
     let _ = fs::remove_file("mini_mmkv.meta");
     for i in 0..10 {
         println!("repeat {}", i);
-        let mmkv = MMKV::new(
-            ".",
-            #[cfg(feature = "encryption")]
-            "88C51C536176AD8A8EE4A06F62EE897E",
-        )
-        .unwrap();
+        let mmkv = open_default();
         let result = mmkv.get("integration_test");
         if i == 0 {
             assert_eq!(result, Err(KeyNotFound));
@@ -23,13 +26,60 @@ fn integration_test() {
         mmkv.put("integration_test", i.to_string().as_str())
             .unwrap();
     }
-    let mmkv = MMKV::new(
-        ".",
-        #[cfg(feature = "encryption")]
-        "88C51C536176AD8A8EE4A06F62EE897E",
-    )
-    .unwrap();
+    let mmkv = open_default();
     mmkv.clear_data().unwrap();
     let _ = fs::remove_file("mini_mmkv");
     let _ = fs::remove_file("mini_mmkv.meta");
 }
+
+const CHILD_ENV: &str = "MMKV_MULTI_PROCESS_CHILD";
+
+fn open_shared() -> MMKV {
+    let builder = MMKV::builder(".")
+        .file_name("multi_process_mmkv")
+        .multi_process(true);
+    #[cfg(feature = "encryption")]
+    let builder = builder.encryption_key("88C51C536176AD8A8EE4A06F62EE897E");
+    builder.open().unwrap()
+}
+
+#[test]
+fn multi_process_test() {
+    if let Ok(id) = env::var(CHILD_ENV) {
+        // Spawned by the test below as another process
+        let mmkv = open_shared();
+        for i in 0..200 {
+            mmkv.put(&format!("{id}_{i}"), i).unwrap();
+            mmkv.put("last_writer", id.as_str()).unwrap();
+        }
+        mmkv.flush().unwrap();
+        return;
+    }
+    let _ = fs::remove_file("multi_process_mmkv");
+    let _ = fs::remove_file("multi_process_mmkv.meta");
+    let mmkv = open_shared();
+    let children: Vec<_> = (0..4)
+        .map(|id| {
+            Command::new(env::current_exe().unwrap())
+                .args(["multi_process_test", "--exact", "--quiet"])
+                .env(CHILD_ENV, id.to_string())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for i in 0..200 {
+        mmkv.put(&format!("parent_{i}"), i).unwrap();
+    }
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    for id in ["0", "1", "2", "3", "parent"] {
+        for i in 0..200 {
+            assert_eq!(mmkv.get(&format!("{id}_{i}")), Ok(i));
+        }
+    }
+    assert_eq!(mmkv.count(), Ok(1001));
+    mmkv.clear_data().unwrap();
+    let _ = fs::remove_file("multi_process_mmkv");
+    let _ = fs::remove_file("multi_process_mmkv.meta");
+}